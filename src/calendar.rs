@@ -0,0 +1,51 @@
+use std::slice;
+use crate::crd::{ScalingCalendar, ServiceScaler, TimeRangeSpec};
+use kube::{Api, Client, ResourceExt};
+use kube::runtime::events::EventType;
+use kube::runtime::reflector::{ObjectRef, Store};
+use log::{error, info};
+use crate::events;
+use crate::events::REASON_INVALID_CALENDAR_ENTRY;
+use crate::util::check_time_range;
+
+/// time ranges of every calendar referenced by the ServiceScaler, missing calendars are skipped and entries that do
+/// not parse are skipped with a Warning event
+pub async fn time_ranges(client: Client, service_scaler: &ServiceScaler) -> Vec<TimeRangeSpec> {
+    let api: Api<ScalingCalendar> = Api::all(client.clone());
+    let mut time_ranges = vec![];
+    for calendar in service_scaler.spec.calendars.clone().unwrap_or_default() {
+        match api.get(&calendar).await {
+            Ok(calendar) => {
+                for time_range in calendar.spec.entries.iter().cloned().map(TimeRangeSpec::from) {
+                    // checked as applied, with the ServiceScaler level time zone filled in
+                    let effective_time_range = service_scaler.spec.effective_time_range_spec(slice::from_ref(&time_range));
+                    match effective_time_range.last().map_or(Ok(()), check_time_range) {
+                        Ok(_) => time_ranges.push(time_range),
+                        Err(e) => {
+                            error!("[{}] skipping calendar:{} entry:{}! err: {:?}", service_scaler.name_any(), calendar.name_any(), time_range.label(), e);
+                            events::publish(client.clone(), service_scaler, EventType::Warning, REASON_INVALID_CALENDAR_ENTRY,
+                                format!("skipped calendar:{} entry:{}: {}", calendar.name_any(), time_range.label(), e)).await;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                error!("[{}] skipping calendar:{}! err: {:?}", service_scaler.name_any(), calendar, e);
+            }
+        }
+    }
+    time_ranges
+}
+
+/// ServiceScalers (from the controller cache) referencing the calendar, used to requeue them when it changes
+pub fn referencing_scalers(store: &Store<ServiceScaler>, calendar: &ScalingCalendar) -> Vec<ObjectRef<ServiceScaler>> {
+    let calendar_name = calendar.name_any();
+    let service_scalers: Vec<ObjectRef<ServiceScaler>> = store.state().iter()
+        .filter(|service_scaler| service_scaler.spec.calendars.as_ref().is_some_and(|calendars| calendars.contains(&calendar_name)))
+        .map(|service_scaler| ObjectRef::from_obj(service_scaler.as_ref()))
+        .collect();
+    if !service_scalers.is_empty() {
+        info!("[{}] calendar change detected! requeueing {} service scalers!", calendar_name, service_scalers.len());
+    }
+    service_scalers
+}
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+use chrono::{SecondsFormat, Utc, Weekday};
 use kube::{CustomResource};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -15,16 +17,121 @@ derive = "PartialEq",
 namespaced
 )]
 #[kube(status = "ServiceScalerStatus")]
+#[kube(printcolumn = r#"{"name":"Active", "type":"string", "jsonPath":".status.activeTimeRangeName"}"#)]
+#[kube(printcolumn = r#"{"name":"Min", "type":"integer", "jsonPath":".status.observedMinReplicas"}"#)]
+#[kube(printcolumn = r#"{"name":"Max", "type":"integer", "jsonPath":".status.observedMaxReplicas"}"#)]
+#[kube(printcolumn = r#"{"name":"Paused", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Paused\")].status"}"#)]
+#[kube(printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#)]
 pub struct ServiceScalerSpec {
     pub hpa: HpaSpec,
+    /// workload scaled by the generated hpa, defaults to the `apps/v1` Deployment named after the ServiceScaler
+    #[serde(rename = "scaleTargetRef")]
+    pub scale_target_ref: Option<ScaleTargetRef>,
+    /// sets the controller owner reference on a pre-existing hpa of the same name (unless another controller owns it)
+    #[serde(rename = "adoptExistingHpa")]
+    pub adopt_existing_hpa: Option<bool>,
     #[serde(rename = "timeRangeSpec")]
     pub time_range_spec: Vec<TimeRangeSpec>,
+    /// IANA time zone name (Ex: `Europe/London`) applied to every time range that does not set its own
+    #[serde(rename = "timeZone")]
+    pub time_zone: Option<String>,
+    /// names of cluster scoped `ScalingCalendar`s whose entries are applied as additional time ranges
+    pub calendars: Option<Vec<String>>,
+    /// ramp-up/down duration (Ex: `30m`) applied to every time range that does not set its own
+    #[serde(rename = "rampDuration")]
+    pub ramp_duration: Option<String>,
+    /// ramp curve applied to every time range that does not set its own
+    #[serde(rename = "rampCurve")]
+    pub ramp_curve: Option<RampCurve>,
+    /// limits applied when minReplicas/maxReplicas move up
+    #[serde(rename = "scaleUp")]
+    pub scale_up: Option<StepPolicy>,
+    /// limits applied when minReplicas/maxReplicas move down
+    #[serde(rename = "scaleDown")]
+    pub scale_down: Option<StepPolicy>,
+}
+
+impl ServiceScalerSpec {
+    /// time ranges followed by [calendar_time_ranges], with the ServiceScaler level time zone and ramp settings filled
+    /// in where a range does not set its own
+    pub fn effective_time_range_spec(&self, calendar_time_ranges: &[TimeRangeSpec]) -> Vec<TimeRangeSpec> {
+        self.time_range_spec.iter().chain(calendar_time_ranges).map(|time_range| {
+            let mut time_range = time_range.clone();
+            if time_range.time_zone.is_none() {
+                time_range.time_zone = self.time_zone.clone();
+            }
+            if time_range.ramp_duration.is_none() {
+                time_range.ramp_duration = self.ramp_duration.clone();
+            }
+            if time_range.ramp_curve.is_none() {
+                time_range.ramp_curve = self.ramp_curve.clone();
+            }
+            time_range
+        }).collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
 pub enum TimeRangeType {
     ZonedTime,
     ZonedDateTime,
+    Cron,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub struct ScaleTargetRef {
+    /// defaults to `apps/v1`
+    #[serde(rename = "apiVersion")]
+    pub api_version: Option<String>,
+    pub kind: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub struct StepPolicy {
+    /// max replicas added/removed per step, at least `1`
+    #[serde(rename = "maxReplicasPerStep")]
+    #[schemars(range(min = 1))]
+    pub max_replicas_per_step: Option<i32>,
+    /// max percent (of the current value) added/removed per step, at least one replica, at least `1`
+    #[serde(rename = "maxPercentPerStep")]
+    #[schemars(range(min = 1))]
+    pub max_percent_per_step: Option<i32>,
+    /// min time (Ex: `10m`) between two steps in the same direction
+    #[serde(rename = "stabilizationDelay")]
+    pub stabilization_delay: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub enum RampCurve {
+    Linear,
+    Exponential,
+    Immediate,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub enum DayOfWeek {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl From<DayOfWeek> for Weekday {
+    fn from(day: DayOfWeek) -> Self {
+        match day {
+            DayOfWeek::Mon => Weekday::Mon,
+            DayOfWeek::Tue => Weekday::Tue,
+            DayOfWeek::Wed => Weekday::Wed,
+            DayOfWeek::Thu => Weekday::Thu,
+            DayOfWeek::Fri => Weekday::Fri,
+            DayOfWeek::Sat => Weekday::Sat,
+            DayOfWeek::Sun => Weekday::Sun,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
@@ -37,9 +144,13 @@ pub struct HpaSpec {
     pub target_cpu_utilization: Option<i32>,
     #[serde(rename = "targetMemoryUtilization")]
     pub target_memory_utilization: Option<i32>,
+    /// Pods, Object, External and ContainerResource metrics
+    pub metrics: Option<Vec<CustomMetricSpec>>,
+    /// scale-up/down behavior of the hpa itself
+    pub behavior: Option<HpaBehavior>,
 }
 
-#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone, Default)]
 pub struct HpaOverrideSpec {
     #[serde(rename = "minReplicas")]
     pub min_replicas: Option<i32>,
@@ -49,6 +160,102 @@ pub struct HpaOverrideSpec {
     pub target_cpu_utilization: Option<i32>,
     #[serde(rename = "targetMemoryUtilization")]
     pub target_memory_utilization: Option<i32>,
+    /// replaces the target of the default metric with the same type, name and container, or adds the metric
+    pub metrics: Option<Vec<CustomMetricSpec>>,
+    /// replaces the default `scaleUp`/`scaleDown` rules that are set here
+    pub behavior: Option<HpaBehavior>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub enum CustomMetricType {
+    Pods,
+    Object,
+    External,
+    ContainerResource,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub enum MetricTargetType {
+    Utilization,
+    Value,
+    AverageValue,
+}
+
+impl From<HpaSpec> for HpaOverrideSpec {
+    fn from(hpa_spec: HpaSpec) -> Self {
+        HpaOverrideSpec {
+            min_replicas: Some(hpa_spec.min_replicas),
+            max_replicas: Some(hpa_spec.max_replicas),
+            target_cpu_utilization: hpa_spec.target_cpu_utilization,
+            target_memory_utilization: hpa_spec.target_memory_utilization,
+            metrics: hpa_spec.metrics,
+            behavior: hpa_spec.behavior,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub struct CustomMetricTarget {
+    #[serde(rename = "type")]
+    pub type_: MetricTargetType,
+    /// quantity, Ex: `100` or `500m`
+    pub value: Option<String>,
+    /// quantity, Ex: `100` or `500m`
+    #[serde(rename = "averageValue")]
+    pub average_value: Option<String>,
+    #[serde(rename = "averageUtilization")]
+    pub average_utilization: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub struct CustomMetricSpec {
+    #[serde(rename = "type")]
+    pub type_: CustomMetricType,
+    /// metric name, the resource name (Ex: `cpu`) for ContainerResource metrics
+    pub name: String,
+    /// ContainerResource metrics only
+    pub container: Option<String>,
+    /// label selector (`matchLabels`) narrowing down the metric
+    pub selector: Option<BTreeMap<String, String>>,
+    /// Object metrics only
+    #[serde(rename = "describedObject")]
+    pub described_object: Option<ScaleTargetRef>,
+    pub target: CustomMetricTarget,
+}
+
+impl CustomMetricSpec {
+    /// whether both specs describe the same metric (regardless of target)
+    pub fn same_metric(&self, other: &CustomMetricSpec) -> bool {
+        self.type_ == other.type_ && self.name == other.name && self.container == other.container
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub struct HpaBehavior {
+    #[serde(rename = "scaleUp")]
+    pub scale_up: Option<HpaScalingRules>,
+    #[serde(rename = "scaleDown")]
+    pub scale_down: Option<HpaScalingRules>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub struct HpaScalingRules {
+    #[serde(rename = "stabilizationWindowSeconds")]
+    pub stabilization_window_seconds: Option<i32>,
+    /// one of `Max`, `Min` or `Disabled`
+    #[serde(rename = "selectPolicy")]
+    pub select_policy: Option<String>,
+    pub policies: Option<Vec<HpaScalingPolicy>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub struct HpaScalingPolicy {
+    /// one of `Pods` or `Percent`
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub value: i32,
+    #[serde(rename = "periodSeconds")]
+    pub period_seconds: i32,
 }
 
 #[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
@@ -58,22 +265,180 @@ pub struct ReplicaSpec {
 
 #[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
 pub struct TimeRangeSpec {
+    /// used in logs, status and events, Ex: `weekday-peak`
+    pub name: Option<String>,
     pub kind: TimeRangeType,
     pub from: String,
     pub to: String,
+    /// restricts a ZonedTime range to the listed days, a range crossing midnight belongs to the day it starts on, not
+    /// allowed on other kinds
+    #[serde(rename = "daysOfWeek")]
+    pub days_of_week: Option<Vec<DayOfWeek>>,
+    /// IANA time zone name (Ex: `Europe/London`), `from`/`to` are then given without an offset
+    #[serde(rename = "timeZone")]
+    pub time_zone: Option<String>,
+    /// resolves overlapping ranges, the matching range with the highest priority wins (default `0`), ties go to the
+    /// range declared last
+    pub priority: Option<i32>,
+    /// the ramp reaches the override this long (Ex: `15m`) before `from`
+    #[serde(rename = "leadTime")]
+    pub lead_time: Option<String>,
+    /// the ramp back to the defaults ends this long (Ex: `15m`) after `to`
+    #[serde(rename = "lagTime")]
+    pub lag_time: Option<String>,
+    /// ramp-up/down duration (Ex: `30m`) towards the boundaries of this range
+    #[serde(rename = "rampDuration")]
+    pub ramp_duration: Option<String>,
+    #[serde(rename = "rampCurve")]
+    pub ramp_curve: Option<RampCurve>,
     #[serde(rename = "replicaSpec")]
     pub replica_spec: ReplicaSpec,
 }
 
-#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+impl TimeRangeSpec {
+    /// name of the time range, `from-to` if unnamed
+    pub fn label(&self) -> String {
+        self.name.clone().unwrap_or_else(|| format!("{}-{}", self.from, self.to))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone, Default)]
 pub struct ServiceScalerStatus {
     #[serde(rename = "timeRangeMatch")]
     pub time_range_match: bool,
+    /// indices of every time range matching the current timestamp, in the same index space as `activeTimeRange`:
+    /// `timeRangeSpec` followed by the (valid) entries of the referenced calendars in `calendars` order
+    #[serde(rename = "matchedTimeRanges")]
+    pub matched_time_ranges: Option<Vec<usize>>,
     #[serde(rename = "lastObservedGeneration")]
     pub last_observed_generation: Option<i64>,
+    /// hpa created (or adopted) by the ServiceScaler, only set once the create succeeded
+    #[serde(rename = "hpaName")]
+    pub hpa_name: Option<String>,
     #[serde(rename = "lastKnownConfig")]
     pub last_known_config: HpaOverrideSpec,
     #[serde(rename = "lastUpdatedTime")]
     pub last_updated_time: String,
+    /// `rfc3339` time minReplicas/maxReplicas last moved up
+    #[serde(rename = "lastScaleUpTime")]
+    pub last_scale_up_time: Option<String>,
+    /// `rfc3339` time minReplicas/maxReplicas last moved down
+    #[serde(rename = "lastScaleDownTime")]
+    pub last_scale_down_time: Option<String>,
+    /// index (into `timeRangeSpec`, calendar entries following) of the applied time range, see `matchedTimeRanges`
+    #[serde(rename = "activeTimeRange")]
+    pub active_time_range: Option<usize>,
+    /// name (or `from-to` if unnamed) of the applied time range
+    #[serde(rename = "activeTimeRangeName")]
+    pub active_time_range_name: Option<String>,
+    /// `rfc3339` time of the next `from`/`to` boundary
+    #[serde(rename = "nextTransitionTime")]
+    pub next_transition_time: Option<String>,
+    /// minReplicas the next boundary moves to
+    #[serde(rename = "nextMinReplicas")]
+    pub next_min_replicas: Option<i32>,
+    /// maxReplicas the next boundary moves to
+    #[serde(rename = "nextMaxReplicas")]
+    pub next_max_replicas: Option<i32>,
+    /// ramp towards the next boundary, Ex: `3 of 6`
+    #[serde(rename = "rampStep")]
+    pub ramp_step: Option<String>,
+    /// minReplicas of the hpa after the reconcile (as applied)
+    #[serde(rename = "observedMinReplicas")]
+    pub observed_min_replicas: Option<i32>,
+    /// maxReplicas of the hpa after the reconcile (as applied)
+    #[serde(rename = "observedMaxReplicas")]
+    pub observed_max_replicas: Option<i32>,
+    /// `Ready`, `Progressing`, `Paused` and `Degraded` conditions
+    pub conditions: Option<Vec<ServiceScalerCondition>>,
+}
+
+impl ServiceScalerStatus {
+    /// sets the condition of type [type_], `lastTransitionTime` only moves when the status flips
+    pub fn set_condition(&mut self, type_: &str, status: bool, reason: &str, message: &str) {
+        let status = if status { "True" } else { "False" }.to_string();
+        let conditions = self.conditions.get_or_insert_with(Vec::new);
+        let last_transition_time = conditions.iter()
+            .find(|condition| condition.type_ == type_ && condition.status == status)
+            .map(|condition| condition.last_transition_time.clone())
+            .unwrap_or_else(|| Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+        conditions.retain(|condition| condition.type_ != type_);
+        conditions.push(ServiceScalerCondition {
+            type_: type_.to_string(),
+            status,
+            reason: reason.to_string(),
+            message: message.to_string(),
+            last_transition_time,
+        });
+    }
+}
+
+// condition types
+/// the hpa matches the desired spec
+pub const CONDITION_READY: &str = "Ready";
+/// minReplicas/maxReplicas are moving (ramping) towards a time range target
+pub const CONDITION_PROGRESSING: &str = "Progressing";
+/// the kill switch annotation on the hpa disables the ServiceScaler
+pub const CONDITION_PAUSED: &str = "Paused";
+/// the last reconcile failed
+pub const CONDITION_DEGRADED: &str = "Degraded";
 
-}
\ No newline at end of file
+/// Kubernetes style condition, `status` is one of `True`, `False` or `Unknown`
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub struct ServiceScalerCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    /// CamelCase reason of the last transition
+    pub reason: String,
+    pub message: String,
+    /// `rfc3339` time the status last changed
+    #[serde(rename = "lastTransitionTime")]
+    pub last_transition_time: String,
+}
+
+/// Cluster scoped calendar of named date ranges (Ex: sale days, public holidays) shared across ServiceScalers, a
+/// `ServiceScaler` references it by name under `calendars`.
+#[derive(CustomResource, Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[kube(
+group = "scaler.udaan.io",
+version = "v1",
+kind = "ScalingCalendar",
+plural = "scalingcalendars",
+derive = "PartialEq"
+)]
+pub struct ScalingCalendarSpec {
+    pub entries: Vec<CalendarEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema, PartialEq, Clone)]
+pub struct CalendarEntry {
+    pub name: String,
+    /// `rfc3339` (or `YYYY-MM-DDTHH:MM:SS` if a time zone is given)
+    pub from: String,
+    pub to: String,
+    #[serde(rename = "timeZone")]
+    pub time_zone: Option<String>,
+    pub priority: Option<i32>,
+    #[serde(rename = "replicaSpec")]
+    pub replica_spec: ReplicaSpec,
+}
+
+impl From<CalendarEntry> for TimeRangeSpec {
+    fn from(entry: CalendarEntry) -> Self {
+        TimeRangeSpec {
+            name: Some(entry.name),
+            kind: TimeRangeType::ZonedDateTime,
+            from: entry.from,
+            to: entry.to,
+            days_of_week: None,
+            time_zone: entry.time_zone,
+            priority: entry.priority,
+            lead_time: None,
+            lag_time: None,
+            ramp_duration: None,
+            ramp_curve: None,
+            replica_spec: entry.replica_spec,
+        }
+    }
+}
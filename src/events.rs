@@ -0,0 +1,65 @@
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::{Api, Client, Resource, ResourceExt};
+use kube::api::{ApiResource, DynamicObject, GroupVersionKind};
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use log::warn;
+use crate::crd::ServiceScaler;
+use crate::util::{key, FIELD_MANAGER, K8S_DEPLOYMENT_VERSION, POD_NAME};
+
+// event reasons
+pub const REASON_HPA_CREATED: &str = "HpaCreated";
+pub const REASON_HPA_ADOPTED: &str = "HpaAdopted";
+pub const REASON_HPA_RECREATED: &str = "HpaRecreated";
+pub const REASON_TIME_RANGE_ENTERED: &str = "TimeRangeEntered";
+pub const REASON_TIME_RANGE_EXITED: &str = "TimeRangeExited";
+pub const REASON_RAMP_STEP: &str = "RampStep";
+pub const REASON_SCALED: &str = "Scaled";
+pub const REASON_KILL_SWITCH: &str = "KillSwitch";
+pub const REASON_RECONCILE_FAILED: &str = "ReconcileFailed";
+pub const REASON_FIELD_CONFLICT: &str = "FieldConflict";
+pub const REASON_INVALID_CALENDAR_ENTRY: &str = "InvalidCalendarEntry";
+
+fn reporter() -> Reporter {
+    Reporter {
+        controller: FIELD_MANAGER.to_string(),
+        instance: Some(POD_NAME.clone()),
+    }
+}
+
+/// reference to the workload scaled by the hpa (`scaleTargetRef`, the Deployment of the same name by default), its
+/// uid is looked up as `kubectl describe` only lists events carrying it
+async fn scale_target_reference(client: Client, service_scaler: &ServiceScaler) -> Result<ObjectReference, kube::Error> {
+    let namespace = service_scaler.namespace().unwrap_or_default();
+    let (api_version, kind, name) = match &service_scaler.spec.scale_target_ref {
+        Some(target) => (target.api_version.clone().unwrap_or(K8S_DEPLOYMENT_VERSION.to_string()), target.kind.clone(), target.name.clone()),
+        None => (K8S_DEPLOYMENT_VERSION.to_string(), "Deployment".to_string(), service_scaler.name_any()),
+    };
+    let (group, version) = api_version.rsplit_once('/').unwrap_or(("", api_version.as_str()));
+    let api_resource = ApiResource::from_gvk(&GroupVersionKind::gvk(group, version, &kind));
+    let target = Api::<DynamicObject>::namespaced_with(client, &namespace, &api_resource).get(&name).await?;
+    Ok(target.object_ref(&api_resource))
+}
+
+/// publishes an event on the ServiceScaler (shown by `kubectl describe servicescaler`) and on its scale target (shown
+/// by `kubectl describe deployment`), failures are only logged
+pub async fn publish(client: Client, service_scaler: &ServiceScaler, type_: EventType, reason: &str, note: String) {
+    let service_scaler_key = key(&service_scaler.namespace().unwrap_or_default(), &service_scaler.name_any());
+    let mut references = vec![service_scaler.object_ref(&())];
+    match scale_target_reference(client.clone(), service_scaler).await {
+        Ok(reference) => references.push(reference),
+        Err(e) => warn!("[{}] unable to find the scale target, event:{} only published on the service scaler! err: {:?}", service_scaler_key, reason, e),
+    }
+    for reference in references {
+        let event = Event {
+            type_,
+            reason: reason.to_string(),
+            note: Some(note.clone()),
+            action: "Scale".to_string(),
+            secondary: None,
+        };
+        let recorder = Recorder::new(client.clone(), reporter(), reference);
+        if let Err(e) = recorder.publish(event).await {
+            warn!("[{}] unable to publish event:{}! err: {:?}", service_scaler_key, reason, e);
+        }
+    }
+}
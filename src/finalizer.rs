@@ -1,5 +1,6 @@
 use crate::crd::ServiceScaler;
-use kube::api::{Patch, PatchParams};
+use crate::util::patch_params;
+use kube::api::Patch;
 use kube::{Api, Client, Error};
 use serde_json::{json, Value};
 
@@ -12,7 +13,7 @@ pub async fn add(client: Client, namespace: &str, name: &str) -> Result<ServiceS
         }
     });
     let patch: Patch<&Value> = Patch::Merge(&finalizer);
-    api.patch(name, &PatchParams::default(), &patch).await
+    api.patch(name, &patch_params(), &patch).await
 }
 
 
@@ -25,5 +26,5 @@ pub async fn delete(client: Client, namespace: &str, name: &str) -> Result<Servi
         }
     });
     let patch: Patch<&Value> = Patch::Merge(&finalizer);
-    api.patch(name, &PatchParams::default(), &patch).await
+    api.patch(name, &patch_params(), &patch).await
 }
\ No newline at end of file
@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use chrono::Utc;
+use kube::runtime::reflector::Store;
+use crate::crd::ServiceScaler;
+use crate::util::LIVENESS_DEADLINE;
+
+/// state behind `/healthz` and `/readyz`
+pub struct Health {
+    store: Store<ServiceScaler>,
+    /// the ServiceScaler watcher has listed every object once
+    synced: AtomicBool,
+    /// holds the lease, always set without leader election
+    leading: AtomicBool,
+    /// unix timestamp (seconds) of the last reconcile, or of the moment leadership was acquired
+    last_reconcile: AtomicI64,
+}
+
+impl Health {
+    pub fn new(store: Store<ServiceScaler>) -> Self {
+        Health {
+            store,
+            synced: AtomicBool::new(false),
+            leading: AtomicBool::new(false),
+            last_reconcile: AtomicI64::new(Utc::now().timestamp()),
+        }
+    }
+
+    /// marks the watcher synced once the controller cache is populated
+    pub async fn wait_for_sync(&self) {
+        if self.store.wait_until_ready().await.is_ok() {
+            self.synced.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn lead(&self) {
+        self.last_reconcile.store(Utc::now().timestamp(), Ordering::Relaxed);
+        self.leading.store(true, Ordering::Relaxed);
+    }
+
+    pub fn reconciled(&self) {
+        self.last_reconcile.store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    /// ready once the watcher synced on the leader, followers are never ready
+    pub fn ready(&self) -> bool {
+        self.leading.load(Ordering::Relaxed) && self.synced.load(Ordering::Relaxed)
+    }
+
+    /// live unless the leader has ServiceScalers to reconcile and none was reconciled within [LIVENESS_DEADLINE]
+    pub fn live(&self) -> bool {
+        if !self.leading.load(Ordering::Relaxed) || self.store.state().is_empty() {
+            return true;
+        }
+        Utc::now().timestamp() - self.last_reconcile.load(Ordering::Relaxed) <= *LIVENESS_DEADLINE as i64
+    }
+}
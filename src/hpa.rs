@@ -1,36 +1,495 @@
-use std::collections::BTreeMap;
-use k8s_openapi::api::autoscaling::v2beta2::{CrossVersionObjectReference, MetricSpec, MetricTarget, ResourceMetricSource};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use k8s_openapi::api::autoscaling::v2beta2::{ContainerResourceMetricSource, CrossVersionObjectReference, ExternalMetricSource, HPAScalingPolicy, HPAScalingRules, HorizontalPodAutoscalerBehavior, MetricIdentifier, MetricSpec, MetricTarget, ObjectMetricSource, PodsMetricSource, ResourceMetricSource};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use k8s_openapi::api::autoscaling::v2beta2::{HorizontalPodAutoscaler, HorizontalPodAutoscalerSpec};
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
-use kube::{Api, Client, Error};
-use kube::api::{DeleteParams, Patch, PatchParams, PostParams};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta, OwnerReference};
+use kube::{discovery, Api, Client, Error, Resource, ResourceExt};
+use kube::runtime::reflector::{ObjectRef, Store};
+use kube::api::{ApiResource, DeleteParams, DynamicObject, GroupVersionKind, Patch, PatchParams};
 use kube::error::{ErrorResponse};
 use serde_json::{json, Value};
-use log::{info};
-use crate::crd::HpaSpec;
-use crate::util::{K8S_AUTOSCALING_VERSION, K8S_DEPLOYMENT_VERSION, key, SERVICE_SCALER_MANAGED_ANNOTATION, SERVICE_SCALER_NOTE_KEY, SERVICE_SCALER_NOTE_VALUE};
+use log::{error, info};
+use crate::crd::{CustomMetricSpec, CustomMetricType, HpaBehavior, HpaScalingRules, HpaSpec, MetricTargetType, ScaleTargetRef, ServiceScaler};
+use crate::metrics::count_api_error;
+use crate::util::{FIELD_MANAGER, FORCE_CONFLICTS, patch_params, K8S_AUTOSCALING_GROUP, K8S_AUTOSCALING_V2_VERSION, K8S_AUTOSCALING_V2BETA2_VERSION, K8S_AUTOSCALING_VERSION, K8S_DEPLOYMENT_VERSION, key, SERVICE_SCALER_MANAGED_ANNOTATION, SERVICE_SCALER_NOTE_KEY, SERVICE_SCALER_NOTE_VALUE};
 
 
 static DEFAULT_CPU_UTILIZATION: u32 = 80;
 
+/// Pods, Object, External and ContainerResource metric specs of the hpa, Object metrics without `describedObject` and
+/// ContainerResource metrics without `container` are rejected
+pub fn custom_metric_specs(metrics: &Option<Vec<CustomMetricSpec>>) -> Result<Vec<MetricSpec>, crate::Error> {
+    metrics.iter().flatten().map(|metric| {
+        let target = MetricTarget {
+            average_utilization: metric.target.average_utilization,
+            average_value: metric.target.average_value.clone().map(Quantity),
+            type_: match metric.target.type_ {
+                MetricTargetType::Utilization => "Utilization".to_string(),
+                MetricTargetType::Value => "Value".to_string(),
+                MetricTargetType::AverageValue => "AverageValue".to_string(),
+            },
+            value: metric.target.value.clone().map(Quantity),
+        };
+        let identifier = MetricIdentifier {
+            name: metric.name.clone(),
+            selector: metric.selector.clone().map(|match_labels| LabelSelector { match_expressions: None, match_labels: Some(match_labels) }),
+        };
+        let mut spec = MetricSpec {
+            container_resource: None,
+            external: None,
+            object: None,
+            pods: None,
+            resource: None,
+            type_: String::new(),
+        };
+        match metric.type_ {
+            CustomMetricType::Pods => {
+                spec.type_ = "Pods".to_string();
+                spec.pods = Some(PodsMetricSource { metric: identifier, target });
+            }
+            CustomMetricType::Object => {
+                let described_object = metric.described_object.as_ref()
+                    .ok_or_else(|| crate::Error::UserInputError(format!("Object metric {:?} requires describedObject", metric.name)))?;
+                spec.type_ = "Object".to_string();
+                spec.object = Some(ObjectMetricSource {
+                    described_object: CrossVersionObjectReference {
+                        api_version: described_object.api_version.clone(),
+                        kind: described_object.kind.clone(),
+                        name: described_object.name.clone(),
+                    },
+                    metric: identifier,
+                    target,
+                });
+            }
+            CustomMetricType::External => {
+                spec.type_ = "External".to_string();
+                spec.external = Some(ExternalMetricSource { metric: identifier, target });
+            }
+            CustomMetricType::ContainerResource => {
+                let container = metric.container.clone().filter(|container| !container.is_empty())
+                    .ok_or_else(|| crate::Error::UserInputError(format!("ContainerResource metric {:?} requires container", metric.name)))?;
+                spec.type_ = "ContainerResource".to_string();
+                spec.container_resource = Some(ContainerResourceMetricSource {
+                    container,
+                    name: metric.name.clone(),
+                    target,
+                });
+            }
+        }
+        Ok(spec)
+    }).collect()
+}
+
+fn hpa_scaling_rules(rules: &HpaScalingRules) -> HPAScalingRules {
+    HPAScalingRules {
+        policies: rules.policies.as_ref().map(|policies| policies.iter().map(|policy| HPAScalingPolicy {
+            period_seconds: policy.period_seconds,
+            type_: policy.type_.clone(),
+            value: policy.value,
+        }).collect()),
+        select_policy: rules.select_policy.clone(),
+        stabilization_window_seconds: rules.stabilization_window_seconds,
+    }
+}
+
+/// scale-up/down behavior of the hpa
+pub fn hpa_behavior(behavior: &Option<HpaBehavior>) -> Option<HorizontalPodAutoscalerBehavior> {
+    behavior.as_ref().map(|behavior| HorizontalPodAutoscalerBehavior {
+        scale_down: behavior.scale_down.as_ref().map(hpa_scaling_rules),
+        scale_up: behavior.scale_up.as_ref().map(hpa_scaling_rules),
+    })
+}
+
+/// whether the hpa behavior matches the desired one, fields not set in [desired] are defaulted by the api server and
+/// not compared
+pub fn behavior_matches(current: &Option<HorizontalPodAutoscalerBehavior>, desired: &Option<HpaBehavior>) -> bool {
+    let rules_match = |current: &Option<HPAScalingRules>, desired: &Option<HpaScalingRules>| match (current, desired.as_ref().map(hpa_scaling_rules)) {
+        (_, None) => true,
+        (None, Some(_)) => false,
+        (Some(current), Some(desired)) => {
+            (desired.policies.is_none() || current.policies == desired.policies)
+                && (desired.select_policy.is_none() || current.select_policy == desired.select_policy)
+                && (desired.stabilization_window_seconds.is_none() || current.stabilization_window_seconds == desired.stabilization_window_seconds)
+        }
+    };
+    match (current, desired) {
+        (_, None) => true,
+        (None, Some(_)) => false,
+        (Some(current), Some(desired)) => rules_match(&current.scale_up, &desired.scale_up) && rules_match(&current.scale_down, &desired.scale_down),
+    }
+}
+
+/// (type, metric name, container) identifying a metric of the hpa regardless of its target
+fn metric_identity(metric: &MetricSpec) -> (String, Option<String>, Option<String>) {
+    let name = if let Some(resource) = &metric.resource {
+        Some(resource.name.clone())
+    } else if let Some(pods) = &metric.pods {
+        Some(pods.metric.name.clone())
+    } else if let Some(object) = &metric.object {
+        Some(object.metric.name.clone())
+    } else if let Some(external) = &metric.external {
+        Some(external.metric.name.clone())
+    } else {
+        metric.container_resource.as_ref().map(|container_resource| container_resource.name.clone())
+    };
+    let container = metric.container_resource.as_ref().map(|container_resource| container_resource.container.clone());
+    (metric.type_.clone(), name, container)
+}
+
+/// value of a kubernetes quantity (Ex: `500m`, `0.5`, `1Gi` or `1e3`), none if it does not parse
+pub fn parse_quantity(quantity: &str) -> Option<f64> {
+    let quantity = quantity.trim();
+    let split = quantity.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '+' || c == '-')).unwrap_or(quantity.len());
+    let (number, suffix) = quantity.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let multiplier = match suffix {
+        "" => 1f64,
+        "n" => 1e-9,
+        "u" => 1e-6,
+        "m" => 1e-3,
+        "k" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "P" => 1e15,
+        "E" => 1e18,
+        "Ki" => 2f64.powi(10),
+        "Mi" => 2f64.powi(20),
+        "Gi" => 2f64.powi(30),
+        "Ti" => 2f64.powi(40),
+        "Pi" => 2f64.powi(50),
+        "Ei" => 2f64.powi(60),
+        exponent if exponent.starts_with(['e', 'E']) => 10f64.powi(exponent[1..].parse().ok()?),
+        _ => return None,
+    };
+    Some(number * multiplier)
+}
+
+/// whether both quantities hold the same value regardless of their format
+fn quantity_matches(current: &Option<Quantity>, desired: &Option<Quantity>) -> bool {
+    match (current, desired) {
+        (None, None) => true,
+        (Some(current), Some(desired)) => match (parse_quantity(&current.0), parse_quantity(&desired.0)) {
+            (Some(current), Some(desired)) => (current - desired).abs() <= 1e-9 * current.abs().max(desired.abs()),
+            _ => current == desired,
+        },
+        _ => false,
+    }
+}
+
+fn metric_target_mut(metric: &mut MetricSpec) -> Option<&mut MetricTarget> {
+    if let Some(resource) = metric.resource.as_mut() {
+        Some(&mut resource.target)
+    } else if let Some(pods) = metric.pods.as_mut() {
+        Some(&mut pods.target)
+    } else if let Some(object) = metric.object.as_mut() {
+        Some(&mut object.target)
+    } else if let Some(external) = metric.external.as_mut() {
+        Some(&mut external.target)
+    } else {
+        metric.container_resource.as_mut().map(|container_resource| &mut container_resource.target)
+    }
+}
+
+/// whether the metric on the hpa matches the desired one, target quantities are compared by value since the api server
+/// normalizes them (Ex: `0.5` is returned as `500m`)
+pub fn metric_matches(current: &MetricSpec, desired: &MetricSpec) -> bool {
+    let (mut current, mut desired) = (current.clone(), desired.clone());
+    if let (Some(current_target), Some(desired_target)) = (metric_target_mut(&mut current), metric_target_mut(&mut desired)) {
+        if !quantity_matches(&current_target.value, &desired_target.value) || !quantity_matches(&current_target.average_value, &desired_target.average_value) {
+            return false;
+        }
+        (current_target.value, current_target.average_value) = (None, None);
+        (desired_target.value, desired_target.average_value) = (None, None);
+    }
+    current == desired
+}
+
+/// whether the metric is managed by the ServiceScaler, i.e. a cpu/memory Resource metric or one of [owned_metrics],
+/// every other metric (including unknown types) is foreign and left as is
+pub fn is_owned_metric(metric: &MetricSpec, owned_metrics: &[MetricSpec]) -> bool {
+    let identity = metric_identity(metric);
+    let owned_resource = metric.type_ == "Resource" && matches!(identity.1.as_deref(), Some("cpu") | Some("memory"));
+    owned_resource || owned_metrics.iter().any(|owned_metric| metric_identity(owned_metric) == identity)
+}
+
+/// cpu/memory utilization and custom metrics of the hpa spec, in the order they are applied
+pub fn desired_metrics(namespace: &str, name: &str, hpa_spec: &HpaSpec) -> Result<Vec<MetricSpec>, crate::Error> {
+    let mut metrics: Vec<MetricSpec> = vec![];
+    // patch memory utilization
+    match hpa_spec.target_memory_utilization {
+        Some(mem_util) => metrics.push(MetricSpec {
+            container_resource: None,
+            external: None,
+            object: None,
+            pods: None,
+            resource: Some(ResourceMetricSource {
+                name: "memory".to_string(),
+                target: MetricTarget {
+                    average_utilization: Some(mem_util),
+                    average_value: None,
+                    type_: "Utilization".to_string(),
+                    value: None,
+                },
+            }),
+            type_: "Resource".to_string(),
+        }),
+        None => info!("[{}] skipping memory utilization patch!", key(namespace, name))
+    }
+
+    // patch cpu utilization
+    match hpa_spec.target_cpu_utilization {
+        Some(cpu_util) => metrics.push(MetricSpec {
+            container_resource: None,
+            external: None,
+            object: None,
+            pods: None,
+            resource: Some(ResourceMetricSource {
+                name: "cpu".to_string(),
+                target: MetricTarget {
+                    average_utilization: Some(cpu_util),
+                    average_value: None,
+                    type_: "Utilization".to_string(),
+                    value: None,
+                },
+            }),
+            type_: "Resource".to_string(),
+        }),
+        None => info!("[{}] skipping cpu utilization patch!", key(namespace, name))
+    }
+
+    // patch custom metrics
+    metrics.extend(custom_metric_specs(&hpa_spec.metrics)?);
+    Ok(metrics)
+}
+
+/// `spec.metrics` to apply: [live_metrics] (found on the hpa) as they are if the managed metrics already match
+/// ([unchanged]), else the [desired] metrics followed by the foreign entries of [live_metrics]
+///
+/// `spec.metrics` is an atomic list, applying it claims ownership of the whole list: re-applying the live list shares
+/// ownership with the other field managers instead of conflicting with them, so only a change of a managed metric
+/// conflicts (see [FORCE_CONFLICTS]). Foreign entries are passed through as raw json so that fields unknown to the
+/// `autoscaling/v2beta2` structs are kept.
+pub fn metrics_to_apply(desired: Vec<MetricSpec>, live_metrics: Vec<Value>, owned_metrics: &[MetricSpec], unchanged: bool) -> Vec<Value> {
+    if unchanged {
+        return live_metrics;
+    }
+    let foreign_metrics: Vec<Value> = live_metrics.into_iter()
+        // metrics the typed structs do not understand are foreign
+        .filter(|metric| serde_json::from_value::<MetricSpec>(metric.clone()).map_or(true, |metric| !is_owned_metric(&metric, owned_metrics)))
+        .collect();
+    if !foreign_metrics.is_empty() {
+        info!("keeping {} foreign metric(s)!", foreign_metrics.len());
+    }
+    desired.iter().map(|metric| json!(metric)).chain(foreign_metrics).collect()
+}
+
+/// controller owner reference pointing at the ServiceScaler, so that the hpa is garbage collected along with it
+pub fn owner_reference(service_scaler_metadata: &ObjectMeta) -> Option<OwnerReference> {
+    service_scaler_metadata.uid.clone().map(|uid| OwnerReference {
+        api_version: ServiceScaler::api_version(&()).to_string(),
+        kind: ServiceScaler::kind(&()).to_string(),
+        name: service_scaler_metadata.name.clone().unwrap_or_default(),
+        uid,
+        controller: Some(true),
+        block_owner_deletion: Some(true),
+    })
+}
+
+/// whether the hpa is controlled by the ServiceScaler
+pub fn is_controlled_by(hpa: &HorizontalPodAutoscaler, service_scaler_metadata: &ObjectMeta) -> bool {
+    hpa.metadata.owner_references.iter().flatten()
+        .any(|owner| owner.controller == Some(true) && Some(&owner.uid) == service_scaler_metadata.uid.as_ref())
+}
+
+/// owner reference to keep on (or adopt onto) the hpa, none if the hpa is not ours and adoption is disabled or
+/// another controller owns it
+pub fn desired_owner_reference(hpa: &HorizontalPodAutoscaler, service_scaler_metadata: &ObjectMeta, adopt: bool) -> Option<OwnerReference> {
+    let other_controller = hpa.metadata.owner_references.iter().flatten()
+        .any(|owner| owner.controller == Some(true) && Some(&owner.uid) != service_scaler_metadata.uid.as_ref());
+    if is_controlled_by(hpa, service_scaler_metadata) || (adopt && !other_controller) {
+        owner_reference(service_scaler_metadata)
+    } else {
+        None
+    }
+}
+
+/// last seen state (resource version, object without it) of every watched hpa, tells spec changes, kill switch flips
+/// and deletions apart from the status and metadata only updates (Ex: the hpa controller writing the current metrics)
+/// and our own applies, which do not need a reconcile
+#[derive(Default)]
+pub struct HpaWatch {
+    seen: Mutex<HashMap<String, (Option<String>, DynamicObject)>>,
+}
+
+impl HpaWatch {
+    /// why the watched hpa needs a reconcile, none if it does not
+    pub fn trigger(&self, hpa: &DynamicObject) -> Option<&'static str> {
+        let mut seen_hpa = hpa.clone();
+        seen_hpa.metadata.resource_version = None;
+        seen_hpa.metadata.managed_fields = None;
+        let hpa_key = key(&hpa.namespace().unwrap_or_default(), &hpa.name_any());
+        let mut seen = self.seen.lock().unwrap();
+        let previous = seen.insert(hpa_key.clone(), (hpa.resource_version(), seen_hpa.clone()));
+        let (previous_resource_version, previous) = match previous {
+            Some(previous) => previous,
+            None => return Some("change"),
+        };
+        if previous_resource_version == hpa.resource_version() {
+            // relisted after a watch restart, nothing moved
+            return None;
+        }
+        if hpa.metadata.deletion_timestamp.is_some() || previous == seen_hpa {
+            // the watch reports a deletion with the last known state, only the resource version moves
+            seen.remove(&hpa_key);
+            return Some("deletion");
+        }
+        if previous.annotations().get(SERVICE_SCALER_MANAGED_ANNOTATION) != hpa.annotations().get(SERVICE_SCALER_MANAGED_ANNOTATION) {
+            return Some("kill switch flip");
+        }
+        if previous.data["spec"] == hpa.data["spec"] || last_spec_manager(hpa).as_deref() == Some(FIELD_MANAGER) {
+            return None;
+        }
+        Some("change")
+    }
+}
+
+/// field manager of the latest write outside the status subresource
+fn last_spec_manager(hpa: &DynamicObject) -> Option<String> {
+    hpa.metadata.managed_fields.iter().flatten()
+        .filter(|entry| entry.subresource.as_deref().unwrap_or_default().is_empty())
+        .max_by_key(|entry| entry.time.as_ref().map(|time| time.0))
+        .and_then(|entry| entry.manager.clone())
+}
+
+/// ServiceScaler (from the controller cache) managing the hpa, used to requeue it on manual edits, deletions or kill
+/// switch flips, hpas are matched by name as pre-existing hpas may not carry an owner reference
+pub fn managing_scaler(store: &Store<ServiceScaler>, hpa_watch: &HpaWatch, hpa: &DynamicObject) -> Option<ObjectRef<ServiceScaler>> {
+    let namespace = hpa.namespace()?;
+    let service_scaler_ref = ObjectRef::new(&hpa.name_any()).within(&namespace);
+    store.get(&service_scaler_ref)?;
+    let trigger = hpa_watch.trigger(hpa)?;
+    info!("[{}] hpa {} detected (resourceVersion:{})! requeueing service scaler!", key(&namespace, &hpa.name_any()), trigger, hpa.resource_version().unwrap_or_default());
+    Some(service_scaler_ref)
+}
+
+/// annotations of the ServiceScaler along with the managed/note annotations
+fn hpa_annotations(service_scaler_metadata: &ObjectMeta) -> BTreeMap<String, String> {
+    let mut annotations = service_scaler_metadata.clone().annotations.unwrap_or_default();
+    annotations.insert(SERVICE_SCALER_MANAGED_ANNOTATION.to_string(), "true".to_string());
+    annotations.insert(SERVICE_SCALER_NOTE_KEY.to_string(), SERVICE_SCALER_NOTE_VALUE.to_string());
+    annotations
+}
+
+/// server-side apply under the service-scaler field manager, conflicts are only forced if FORCE_CONFLICTS is set
+fn apply_params() -> PatchParams {
+    let params = PatchParams::apply(FIELD_MANAGER);
+    if *FORCE_CONFLICTS {
+        params.force()
+    } else {
+        params
+    }
+}
+
+/// field managers named by a server-side apply conflict (409), Ex: `conflict with "argocd-controller" using
+/// autoscaling/v2: .spec.maxReplicas`, none for any other error (including the 409 of a stale resourceVersion)
+pub fn conflicting_managers(err: &Error) -> Option<Vec<String>> {
+    match err {
+        Error::Api(ErrorResponse { code: 409, message, .. }) => {
+            let mut managers: Vec<String> = message.split("conflict with \"").skip(1)
+                .filter_map(|conflict| conflict.split('"').next())
+                .map(|manager| manager.to_string())
+                .collect();
+            managers.sort();
+            managers.dedup();
+            Some(managers).filter(|managers| !managers.is_empty())
+        }
+        _ => None,
+    }
+}
+
+/// logs field ownership conflicts (another field manager owns a field with a different value) explicitly, the caller
+/// surfaces them on the ServiceScaler
+fn surface_conflict(namespace: &str, name: &str, err: Error) -> Error {
+    if let (Some(managers), Error::Api(ErrorResponse { message, .. })) = (conflicting_managers(&err), &err) {
+        error!("[{}] field ownership conflict with {:?}! set FORCE_CONFLICTS=true to take ownership. {}", key(namespace, name), managers, message);
+    }
+    err
+}
+
+/// workload scaled by the hpa, defaults to the `apps/v1` Deployment named after the ServiceScaler
+fn scale_target_ref(name: &str, target: &Option<ScaleTargetRef>) -> CrossVersionObjectReference {
+    match target {
+        Some(target) => CrossVersionObjectReference {
+            api_version: Some(target.api_version.clone().unwrap_or(K8S_DEPLOYMENT_VERSION.to_string())),
+            kind: target.kind.clone(),
+            name: target.name.clone(),
+        },
+        None => CrossVersionObjectReference {
+            api_version: Some(K8S_DEPLOYMENT_VERSION.to_string()),
+            kind: "Deployment".to_string(),
+            name: name.to_string(),
+        },
+    }
+}
+
 #[derive(Clone)]
 pub struct HpaOperator {
     pub client: Client,
+    /// HorizontalPodAutoscaler api served by the cluster, see [HpaOperator::discover]
+    pub api_resource: ApiResource,
 }
 
 impl HpaOperator {
+    /// discovers the autoscaling api version served by the cluster, `autoscaling/v2` is preferred and
+    /// `autoscaling/v2beta2` is the fallback for clusters older than 1.23
+    pub async fn discover(client: &Client) -> Result<ApiResource, Error> {
+        let group = discovery::group(client, K8S_AUTOSCALING_GROUP).await?;
+        let api_version = if group.versions().any(|version| version == K8S_AUTOSCALING_V2_VERSION) {
+            K8S_AUTOSCALING_V2_VERSION
+        } else {
+            K8S_AUTOSCALING_V2BETA2_VERSION
+        };
+        info!("discovered autoscaling versions:{:?} using:{}", group.versions().collect::<Vec<_>>(), api_version);
+        Ok(ApiResource::from_gvk_with_plural(&GroupVersionKind::gvk(K8S_AUTOSCALING_GROUP, api_version, "HorizontalPodAutoscaler"), "horizontalpodautoscalers"))
+    }
+
+    fn api(&self, namespace: &str) -> Api<DynamicObject> {
+        Api::namespaced_with(self.client.clone(), namespace, &self.api_resource)
+    }
+
+    /// the `autoscaling/v2beta2` structs are used for both served versions, the fields managed here are identical
+    fn typed_hpa(&self, hpa: DynamicObject) -> Result<HorizontalPodAutoscaler, Error> {
+        let mut hpa = serde_json::to_value(hpa).map_err(Error::SerdeError)?;
+        hpa["apiVersion"] = json!(K8S_AUTOSCALING_VERSION);
+        serde_json::from_value(hpa).map_err(Error::SerdeError)
+    }
+
+    fn dynamic_hpa(&self, hpa: &HorizontalPodAutoscaler) -> Result<DynamicObject, Error> {
+        let mut hpa = serde_json::to_value(hpa).map_err(Error::SerdeError)?;
+        hpa["apiVersion"] = json!(self.api_resource.api_version);
+        serde_json::from_value(hpa).map_err(Error::SerdeError)
+    }
+
     pub async fn get(&self, namespace: &str, name: &str) -> Result<HorizontalPodAutoscaler, Error> {
-        let api: Api<HorizontalPodAutoscaler> = Api::namespaced(self.client.clone(), namespace);
-        api.get(name).await
+        self.typed_hpa(count_api_error("get", self.api(namespace).get(name).await)?)
     }
 
-    pub async fn create(&self, namespace: &str, name: &str, hpa_spec: &HpaSpec, service_scaler_metadata: &ObjectMeta) -> Result<HorizontalPodAutoscaler, Error> {
-        let api: Api<HorizontalPodAutoscaler> = Api::namespaced(self.client.clone(), namespace);
-        let existing = api.get(name).await;
-        if existing.is_ok() {
+    /// creates the hpa owned by the ServiceScaler, a pre-existing hpa is marked as managed and adopted if [adopt] is set,
+    /// along with whether the hpa is ours (false if a pre-existing hpa was taken over)
+    pub async fn create(&self, namespace: &str, name: &str, hpa_spec: &HpaSpec, target: &Option<ScaleTargetRef>, adopt: bool, service_scaler_metadata: &ObjectMeta) -> Result<(HorizontalPodAutoscaler, bool), crate::Error> {
+        let api = self.api(namespace);
+        let scale_target_ref = scale_target_ref(name, target);
+        let existing = self.get(namespace, name).await;
+        if let Ok(existing) = existing {
             info!("[{}] hpa already exists!", key(namespace, name));
+            // already ours (Ex: the status patch after the create failed), the kill switch annotation is left alone
+            if is_controlled_by(&existing, service_scaler_metadata) {
+                return Ok((existing, true));
+            }
             // add service scaler managed annotation
-            let annotations_patch: Value = json!({
+            let mut metadata_patch: Value = json!({
                 "metadata": {
                     "annotations": {
                         SERVICE_SCALER_MANAGED_ANNOTATION: "true",
@@ -38,23 +497,20 @@ impl HpaOperator {
                     }
                 }
             });
-            api.patch_metadata(name, &PatchParams::default(), &Patch::Merge(&annotations_patch)).await.expect("patch_metadata errored!");
-            existing
+            // adopt, a merge patch replaces the whole owner reference list
+            if let Some(owner_reference) = desired_owner_reference(&existing, service_scaler_metadata, adopt) {
+                if !is_controlled_by(&existing, service_scaler_metadata) {
+                    let mut owner_references = existing.metadata.owner_references.clone().unwrap_or_default();
+                    owner_references.push(owner_reference);
+                    metadata_patch["metadata"]["ownerReferences"] = json!(owner_references);
+                    info!("[{}] adopting hpa!", key(namespace, name));
+                }
+            }
+            count_api_error("patch", api.patch_metadata(name, &patch_params(), &Patch::Merge(&metadata_patch)).await)?;
+            Ok((self.get(namespace, name).await?, false))
         } else {
             // copy over existing annotations and labels
-            let annotations = match service_scaler_metadata.clone().annotations {
-                Some(mut annotations) => {
-                    annotations.insert(SERVICE_SCALER_MANAGED_ANNOTATION.to_string(), "true".to_string());
-                    annotations.insert(SERVICE_SCALER_NOTE_KEY.to_string(), SERVICE_SCALER_NOTE_VALUE.to_string());
-                    annotations
-                }
-                None => {
-                    let mut annotations = BTreeMap::new();
-                    annotations.insert(SERVICE_SCALER_MANAGED_ANNOTATION.to_string(), "true".to_string());
-                    annotations.insert(SERVICE_SCALER_NOTE_KEY.to_string(), SERVICE_SCALER_NOTE_VALUE.to_string());
-                    annotations
-                }
-            };
+            let annotations = hpa_annotations(service_scaler_metadata);
 
             let hpa = if hpa_spec.target_cpu_utilization.is_some() && hpa_spec.target_memory_utilization.is_some() {
                 serde_json::from_value(json!({
@@ -67,11 +523,7 @@ impl HpaOperator {
                         "labels": service_scaler_metadata.clone().labels
                     },
                     "spec": {
-                        "scaleTargetRef": {
-                            "apiVersion": "apps/v2beta2",
-                            "kind": "Deployment",
-                            "name": name
-                        },
+                        "scaleTargetRef": scale_target_ref,
                         "minReplicas": hpa_spec.min_replicas,
                         "maxReplicas": hpa_spec.max_replicas,
                         "metrics": [
@@ -110,11 +562,7 @@ impl HpaOperator {
                         }
                     },
                     "spec": {
-                        "scaleTargetRef": {
-                            "apiVersion": "apps/v2beta2",
-                            "kind": "Deployment",
-                            "name": name
-                        },
+                        "scaleTargetRef": scale_target_ref,
                         "minReplicas": hpa_spec.min_replicas,
                         "maxReplicas": hpa_spec.max_replicas,
                         "metrics": [
@@ -143,11 +591,7 @@ impl HpaOperator {
                         }
                     },
                     "spec": {
-                        "scaleTargetRef": {
-                            "apiVersion": "apps/v2beta2",
-                            "kind": "Deployment",
-                            "name": name
-                        },
+                        "scaleTargetRef": scale_target_ref,
                         "minReplicas": hpa_spec.min_replicas,
                         "maxReplicas": hpa_spec.max_replicas,
                         "metrics": [
@@ -164,87 +608,74 @@ impl HpaOperator {
                         }
                     }))
             };
-            let res = api.create(&PostParams::default(), &hpa.unwrap()).await;
+            let mut hpa: HorizontalPodAutoscaler = hpa.unwrap();
+            hpa.metadata.owner_references = owner_reference(service_scaler_metadata).map(|owner_reference| vec![owner_reference]);
+            let custom_metrics = custom_metric_specs(&hpa_spec.metrics)?;
+            if let Some(spec) = hpa.spec.as_mut() {
+                if hpa_spec.target_cpu_utilization.is_none() && !custom_metrics.is_empty() {
+                    // custom metrics replace the default cpu utilization
+                    spec.metrics = Some(vec![]);
+                }
+                spec.metrics.get_or_insert_with(Vec::new).extend(custom_metrics);
+                spec.behavior = hpa_behavior(&hpa_spec.behavior);
+            }
+            // a rejected apply (Ex: a conflict, 403 or 422) is surfaced by the caller, nothing was created
+            let res = count_api_error("apply", api.patch(name, &apply_params(), &Patch::Apply(&self.dynamic_hpa(&hpa)?)).await)
+                .map_err(|e| surface_conflict(namespace, name, e))?;
+            let hpa = self.typed_hpa(res)?;
             info!("[{}] hpa created!", key(namespace, name));
-            self.patch_metadata(namespace, name, service_scaler_metadata, None).await.expect("patch_metadata errored!");
-            res
+            self.patch_metadata(namespace, name, service_scaler_metadata, None).await?;
+            Ok((hpa, true))
         }
     }
 
 
-    pub async fn patch(&self, namespace: &str, name: &str, hpa_spec: &HpaSpec) -> Result<HorizontalPodAutoscaler, Error> {
-        let api: Api<HorizontalPodAutoscaler> = Api::namespaced(self.client.clone(), namespace);
-        let mut metrics: Vec<MetricSpec> = vec![];
-        // patch memory utilization
-        match hpa_spec.target_memory_utilization {
-            Some(mem_util) => metrics.push(MetricSpec {
-                container_resource: None,
-                external: None,
-                object: None,
-                pods: None,
-                resource: Some(ResourceMetricSource {
-                    name: "memory".to_string(),
-                    target: MetricTarget {
-                        average_utilization: Some(mem_util),
-                        average_value: None,
-                        type_: "Utilization".to_string(),
-                        value: None,
-                    },
-                }),
-                type_: "Resource".to_string(),
-            }),
-            None => info!("[{}] skipping memory utilization patch!", key(namespace, name))
-        }
-
-        // patch cpu utilization
-        match hpa_spec.target_cpu_utilization {
-            Some(cpu_util) => metrics.push(MetricSpec {
-                container_resource: None,
-                external: None,
-                object: None,
-                pods: None,
-                resource: Some(ResourceMetricSource {
-                    name: "cpu".to_string(),
-                    target: MetricTarget {
-                        average_utilization: Some(cpu_util),
-                        average_value: None,
-                        type_: "Utilization".to_string(),
-                        value: None,
-                    },
-                }),
-                type_: "Resource".to_string(),
-            }),
-            None => info!("[{}] skipping cpu utilization patch!", key(namespace, name))
-        }
-
-        // patch minReplicas and maxReplicas
+    /// applies the hpa along with the whole [metrics] list (see [metrics_to_apply]), [owner_reference] is applied
+    /// along to keep its ownership
+    #[allow(clippy::too_many_arguments)]
+    pub async fn patch(&self, namespace: &str, name: &str, hpa_spec: &HpaSpec, target: &Option<ScaleTargetRef>, metrics: Vec<Value>, owner_reference: Option<OwnerReference>, service_scaler_metadata: &ObjectMeta) -> Result<HorizontalPodAutoscaler, Error> {
+        let api = self.api(namespace);
+        // patch minReplicas and maxReplicas, metrics are set as raw json below
         let spec = HorizontalPodAutoscalerSpec {
-            behavior: None,
+            behavior: hpa_behavior(&hpa_spec.behavior),
             max_replicas: hpa_spec.max_replicas,
-            metrics: Some(metrics.to_vec()),
+            metrics: None,
             min_replicas: Some(hpa_spec.min_replicas),
-            scale_target_ref: CrossVersionObjectReference {
-                api_version: Some(K8S_DEPLOYMENT_VERSION.to_string()),
-                kind: "Deployment".to_string(),
-                name: name.to_string(),
-            },
+            scale_target_ref: scale_target_ref(name, target),
         };
 
-        // prepare patch
-        let hpa_patch: Value = json!({
+        // prepare patch, every field set on create is applied again to keep its ownership
+        let mut hpa_patch: Value = json!({
+            "apiVersion": self.api_resource.api_version,
+            "kind": "HorizontalPodAutoscaler",
+            "metadata": {
+                "name": name,
+                "annotations": hpa_annotations(service_scaler_metadata),
+                "labels": service_scaler_metadata.clone().labels
+            },
             "spec": spec
         });
+        hpa_patch["spec"]["metrics"] = json!(metrics);
+        if let Some(owner_reference) = owner_reference {
+            hpa_patch["metadata"]["ownerReferences"] = json!([owner_reference]);
+        }
 
         // apply patch
-        let patch = Patch::Merge(&hpa_patch);
-        let res = api.patch(name, &PatchParams::default(), &patch).await;
-        info!("[{}] patched hpa!", key(namespace, name));
-        res
+        let patch = Patch::Apply(&hpa_patch);
+        let res = count_api_error("apply", api.patch(name, &apply_params(), &patch).await).map_err(|e| surface_conflict(namespace, name, e))?;
+        info!("[{}] applied hpa!", key(namespace, name));
+        self.typed_hpa(res)
+    }
+
+    /// `spec.metrics` of the hpa as raw json, fields unknown to the `autoscaling/v2beta2` structs included
+    pub async fn live_metrics(&self, namespace: &str, name: &str) -> Result<Vec<Value>, Error> {
+        let hpa = count_api_error("get", self.api(namespace).get(name).await)?;
+        Ok(hpa.data["spec"]["metrics"].as_array().cloned().unwrap_or_default())
     }
 
     pub async fn delete(&self, namespace: &str, name: &str) {
-        let api: Api<HorizontalPodAutoscaler> = Api::namespaced(self.client.clone(), namespace);
-        api.delete(name, &DeleteParams::default()).await
+        let api = self.api(namespace);
+        count_api_error("delete", api.delete(name, &DeleteParams::default()).await)
             .map(|_| ())
             .or_else(|err| match err {
                 // Object is already deleted
@@ -255,7 +686,7 @@ impl HpaOperator {
     }
 
     pub async fn patch_metadata(&self, namespace: &str, name: &str, scaler_metadata: &ObjectMeta, hpa_metadata: Option<&ObjectMeta>) -> Result<(), Error> {
-        let api: Api<HorizontalPodAutoscaler> = Api::namespaced(self.client.clone(), namespace);
+        let api = self.api(namespace);
         let mut labels: BTreeMap<String, String> = BTreeMap::new();
         if scaler_metadata.labels.is_some() {
             labels.append(&mut scaler_metadata.clone().labels.unwrap());
@@ -269,9 +700,121 @@ impl HpaOperator {
                     "labels": Some(labels)
                 }
             });
-            api.patch_metadata(name, &PatchParams::default(), &Patch::Merge(&json_patch)).await.expect("patch_metadata errored!");
+            count_api_error("patch", api.patch_metadata(name, &patch_params(), &Patch::Merge(&json_patch)).await)?;
             info!("[{}] patched metadata!", key(namespace, name));
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom_metrics(value: Value) -> Option<Vec<CustomMetricSpec>> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn custom_metric_specs_require_the_object_and_container() {
+        let specs = custom_metric_specs(&custom_metrics(json!([
+            {"type": "Object", "name": "requests", "describedObject": {"apiVersion": "networking.k8s.io/v1", "kind": "Ingress", "name": "main"}, "target": {"type": "Value", "value": "100"}},
+            {"type": "ContainerResource", "name": "cpu", "container": "app", "target": {"type": "Utilization", "averageUtilization": 70}}
+        ]))).unwrap();
+        assert_eq!(specs[0].object.as_ref().map(|object| object.described_object.name.as_str()), Some("main"));
+        assert_eq!(specs[1].container_resource.as_ref().map(|container_resource| container_resource.container.as_str()), Some("app"));
+        assert!(matches!(custom_metric_specs(&custom_metrics(json!([
+            {"type": "Object", "name": "requests", "target": {"type": "Value", "value": "100"}}
+        ]))), Err(crate::Error::UserInputError(_))));
+        assert!(matches!(custom_metric_specs(&custom_metrics(json!([
+            {"type": "ContainerResource", "name": "cpu", "target": {"type": "Utilization", "averageUtilization": 70}}
+        ]))), Err(crate::Error::UserInputError(_))));
+    }
+
+    fn metric(value: Value) -> MetricSpec {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn is_owned_metric_covers_cpu_memory_and_the_scaler_metrics() {
+        let owned_metrics = vec![metric(json!({"type": "Pods", "pods": {"metric": {"name": "queue_depth"}, "target": {"type": "AverageValue", "averageValue": "10"}}}))];
+        assert!(is_owned_metric(&metric(json!({"type": "Resource", "resource": {"name": "cpu", "target": {"type": "Utilization", "averageUtilization": 50}}})), &owned_metrics));
+        // same metric, another target
+        assert!(is_owned_metric(&metric(json!({"type": "Pods", "pods": {"metric": {"name": "queue_depth"}, "target": {"type": "AverageValue", "averageValue": "20"}}})), &owned_metrics));
+        assert!(!is_owned_metric(&metric(json!({"type": "Resource", "resource": {"name": "ephemeral-storage", "target": {"type": "Utilization", "averageUtilization": 50}}})), &owned_metrics));
+        assert!(!is_owned_metric(&metric(json!({"type": "External", "external": {"metric": {"name": "queue_depth"}, "target": {"type": "Value", "value": "10"}}})), &owned_metrics));
+    }
+
+    #[test]
+    fn metrics_to_apply_keeps_foreign_metrics_after_the_desired_ones() {
+        let desired = vec![metric(json!({"type": "Resource", "resource": {"name": "cpu", "target": {"type": "Utilization", "averageUtilization": 70}}}))];
+        let live_metrics = vec![
+            json!({"type": "Resource", "resource": {"name": "cpu", "target": {"type": "Utilization", "averageUtilization": 50}}}),
+            json!({"type": "External", "external": {"metric": {"name": "rps"}, "target": {"type": "Value", "value": "100"}}}),
+            // unknown to the typed structs, passed through as is
+            json!({"type": "Future", "future": {"name": "x"}}),
+        ];
+        let metrics = metrics_to_apply(desired.clone(), live_metrics.clone(), &[], false);
+        assert_eq!(metrics, vec![json!(desired[0]), live_metrics[1].clone(), live_metrics[2].clone()]);
+        // the live list is re-applied as is when the managed metrics already match
+        assert_eq!(metrics_to_apply(desired, live_metrics.clone(), &[], true), live_metrics);
+    }
+
+    #[test]
+    fn conflicting_managers_are_read_from_apply_conflicts() {
+        let conflict = Error::Api(ErrorResponse {
+            status: "Failure".to_string(),
+            message: "Apply failed with 2 conflicts: conflict with \"argocd-controller\" using autoscaling/v2: .spec.maxReplicas, conflict with \"argocd-controller\" using autoscaling/v2: .spec.minReplicas".to_string(),
+            reason: "Conflict".to_string(),
+            code: 409,
+        });
+        assert_eq!(conflicting_managers(&conflict), Some(vec!["argocd-controller".to_string()]));
+        let forbidden = Error::Api(ErrorResponse { status: "Failure".to_string(), message: "forbidden".to_string(), reason: "Forbidden".to_string(), code: 403 });
+        assert_eq!(conflicting_managers(&forbidden), None);
+        let stale = Error::Api(ErrorResponse {
+            status: "Failure".to_string(),
+            message: "Operation cannot be fulfilled on servicescalers.scaler.udaan.io \"dummy\": the object has been modified; please apply your changes to the latest version and try again".to_string(),
+            reason: "Conflict".to_string(),
+            code: 409,
+        });
+        assert_eq!(conflicting_managers(&stale), None);
+    }
+
+    /// hpa `dummy` at [resource_version] with [min_replicas], the managed annotation set to [managed], [manager] having
+    /// written the spec last and [current_replicas] reported in the status
+    fn watched_hpa(resource_version: &str, min_replicas: i32, managed: &str, manager: &str, current_replicas: i32) -> DynamicObject {
+        serde_json::from_value(json!({
+            "apiVersion": "autoscaling/v2",
+            "kind": "HorizontalPodAutoscaler",
+            "metadata": {
+                "name": "dummy",
+                "namespace": "default",
+                "resourceVersion": resource_version,
+                "annotations": {SERVICE_SCALER_MANAGED_ANNOTATION: managed},
+                "managedFields": [
+                    {"manager": "kube-controller-manager", "operation": "Update", "subresource": "status", "time": "2024-03-06T12:10:00Z"},
+                    {"manager": manager, "operation": "Update", "time": "2024-03-06T12:00:00Z"}
+                ]
+            },
+            "spec": {"minReplicas": min_replicas, "maxReplicas": 10},
+            "status": {"currentReplicas": current_replicas}
+        })).unwrap()
+    }
+
+    #[test]
+    fn hpa_watch_triggers_on_spec_changes_kill_switch_flips_and_deletions_only() {
+        let hpa_watch = HpaWatch::default();
+        assert_eq!(hpa_watch.trigger(&watched_hpa("1", 2, "true", FIELD_MANAGER, 2)), Some("change"));
+        // relisted
+        assert_eq!(hpa_watch.trigger(&watched_hpa("1", 2, "true", FIELD_MANAGER, 2)), None);
+        // the hpa controller reporting the current replicas
+        assert_eq!(hpa_watch.trigger(&watched_hpa("2", 2, "true", FIELD_MANAGER, 3)), None);
+        // our own apply
+        assert_eq!(hpa_watch.trigger(&watched_hpa("3", 4, "true", FIELD_MANAGER, 3)), None);
+        // manual edit
+        assert_eq!(hpa_watch.trigger(&watched_hpa("4", 6, "true", "kubectl-edit", 3)), Some("change"));
+        assert_eq!(hpa_watch.trigger(&watched_hpa("5", 6, "false", "kubectl-edit", 3)), Some("kill switch flip"));
+        // the deletion carries the last known state
+        assert_eq!(hpa_watch.trigger(&watched_hpa("6", 6, "false", "kubectl-edit", 3)), Some("deletion"));
+        assert_eq!(hpa_watch.trigger(&watched_hpa("7", 2, "true", FIELD_MANAGER, 2)), Some("change"));
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::Mutex;
+use std::time::Instant;
+use chrono::{Duration, Utc};
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use kube::api::PostParams;
+use kube::{Api, Client, Error};
+use log::{error, info, warn};
+use crate::util::{LEASE_DURATION, LEASE_NAME, LEASE_NAMESPACE, LEASE_RETRY_PERIOD, POD_NAME};
+
+/// Lease based leader election (coordination.k8s.io/v1), only the holder of the lease runs the controller
+pub struct LeaderElector {
+    api: Api<Lease>,
+    identity: String,
+    /// resourceVersion of the lease last seen and the local instant it was first seen, the holder's `renewTime` is
+    /// written with its own clock and is never compared with ours
+    observed: Mutex<Option<(Option<String>, Instant)>>,
+}
+
+impl LeaderElector {
+    pub fn new(client: Client) -> Self {
+        LeaderElector {
+            api: Api::namespaced(client, LEASE_NAMESPACE.as_str()),
+            identity: POD_NAME.clone(),
+            observed: Mutex::new(None),
+        }
+    }
+
+    /// whether the lease went unchanged (not renewed) for its whole duration, as observed by this replica
+    fn expired(&self, lease: &Lease) -> bool {
+        let lease_duration = match lease.spec.as_ref().and_then(|spec| spec.lease_duration_seconds) {
+            Some(lease_duration) => std::time::Duration::from_secs(lease_duration.max(0) as u64),
+            None => return true,
+        };
+        let mut observed = self.observed.lock().unwrap();
+        match observed.as_ref() {
+            Some((resource_version, observed_at)) if *resource_version == lease.metadata.resource_version => observed_at.elapsed() > lease_duration,
+            _ => {
+                *observed = Some((lease.metadata.resource_version.clone(), Instant::now()));
+                false
+            }
+        }
+    }
+
+    fn lease_spec(&self, acquire_time: Option<MicroTime>, lease_transitions: i32) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(*LEASE_DURATION as i32),
+            acquire_time: acquire_time.or(Some(MicroTime(Utc::now()))),
+            renew_time: Some(MicroTime(Utc::now())),
+            lease_transitions: Some(lease_transitions),
+        }
+    }
+
+    /// acquires the lease if it is free or expired, renews it if already held, returns whether this replica leads
+    pub async fn try_acquire_or_renew(&self) -> Result<bool, Error> {
+        let lease = match self.api.get_opt(LEASE_NAME.as_str()).await? {
+            Some(lease) => lease,
+            None => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(LEASE_NAME.clone()),
+                        ..Default::default()
+                    },
+                    spec: Some(self.lease_spec(None, 0)),
+                };
+                return match self.api.create(&PostParams::default(), &lease).await {
+                    Ok(_) => Ok(true),
+                    // another replica created it first
+                    Err(Error::Api(e)) if e.code == 409 => Ok(false),
+                    Err(e) => Err(e),
+                };
+            }
+        };
+
+        let spec = lease.spec.clone().unwrap_or_default();
+        let held = spec.holder_identity.as_deref() == Some(self.identity.as_str());
+        if !held && !self.expired(&lease) {
+            return Ok(false);
+        }
+
+        let mut updated = lease.clone();
+        updated.spec = Some(if held {
+            self.lease_spec(spec.acquire_time, spec.lease_transitions.unwrap_or(0))
+        } else {
+            info!("lease:{} held by {:?} expired! taking over!", LEASE_NAME.as_str(), spec.holder_identity);
+            self.lease_spec(None, spec.lease_transitions.unwrap_or(0) + 1)
+        });
+        // replace carries the resourceVersion, so only one replica wins a concurrent take over
+        match self.api.replace(LEASE_NAME.as_str(), &PostParams::default(), &updated).await {
+            Ok(_) => Ok(true),
+            Err(Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// waits until this replica holds the lease
+    pub async fn acquire(&self) {
+        info!("waiting for lease:{}/{} as {}", LEASE_NAMESPACE.as_str(), LEASE_NAME.as_str(), self.identity);
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => {
+                    info!("acquired lease:{}/{}!", LEASE_NAMESPACE.as_str(), LEASE_NAME.as_str());
+                    return;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("unable to acquire lease:{}! err: {:?}", LEASE_NAME.as_str(), e);
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(*LEASE_RETRY_PERIOD)).await;
+        }
+    }
+
+    /// renews the lease every retry period, returns once leadership is lost (lease taken over or not renewed before
+    /// it expires)
+    pub async fn hold(&self) {
+        let mut last_renew = Utc::now();
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(*LEASE_RETRY_PERIOD)).await;
+            match self.try_acquire_or_renew().await {
+                Ok(true) => last_renew = Utc::now(),
+                Ok(false) => {
+                    error!("lease:{} taken over by another replica!", LEASE_NAME.as_str());
+                    return;
+                }
+                Err(e) => {
+                    warn!("unable to renew lease:{}! err: {:?}", LEASE_NAME.as_str(), e);
+                    // give up a retry period before the lease expires, so that two leaders never overlap
+                    if Utc::now() > last_renew + Duration::seconds(LEASE_DURATION.saturating_sub(*LEASE_RETRY_PERIOD) as i64) {
+                        error!("lease:{} expired without renewal!", LEASE_NAME.as_str());
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// elector of a cluster that is never called, [LeaderElector::expired] only reads the given lease
+    fn elector() -> LeaderElector {
+        let client = Client::try_from(kube::Config::new("http://127.0.0.1:1".parse().unwrap())).unwrap();
+        LeaderElector { api: Api::namespaced(client, "default"), identity: "replica-b".to_string(), observed: Mutex::new(None) }
+    }
+
+    fn lease(resource_version: &str, lease_duration: Option<i32>) -> Lease {
+        Lease {
+            metadata: ObjectMeta { resource_version: Some(resource_version.to_string()), ..Default::default() },
+            spec: Some(LeaseSpec {
+                holder_identity: Some("replica-a".to_string()),
+                lease_duration_seconds: lease_duration,
+                // far in the past by our clock, the holder's renew time is never trusted
+                renew_time: Some(MicroTime(Utc::now() - Duration::days(1))),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn lease_expires_once_unchanged_for_its_duration_as_observed_locally() {
+        let elector = elector();
+        assert!(elector.expired(&lease("1", None)));
+        assert!(!elector.expired(&lease("1", Some(15))));
+        assert!(!elector.expired(&lease("1", Some(15))));
+        // a renewal restarts the observation
+        assert!(!elector.expired(&lease("2", Some(0))));
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert!(elector.expired(&lease("2", Some(0))));
+        assert!(!elector.expired(&lease("3", Some(0))));
+    }
+}
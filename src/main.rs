@@ -1,23 +1,37 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use std::io::Write;
 use chrono::{Local};
 use env_logger::Builder;
 use kube::{Api, Client, Resource, ResourceExt};
-use kube::runtime::Controller;
+use kube::api::{ApiResource, DynamicObject};
+use kube::runtime::{reflector, watcher, Controller, WatchStreamExt};
 use kube::runtime::controller::Action;
+use kube::runtime::events::EventType;
 use kube::runtime::watcher::Config;
 use tokio::time::Duration;
 use futures::stream::StreamExt;
-use crate::crd::{ServiceScaler};
-use crate::hpa::HpaOperator;
+use crate::crd::{ScalingCalendar, ServiceScaler, ServiceScalerStatus};
+use crate::events::{REASON_FIELD_CONFLICT, REASON_HPA_ADOPTED, REASON_HPA_CREATED, REASON_RECONCILE_FAILED};
+use crate::health::Health;
+use crate::hpa::{conflicting_managers, is_controlled_by, HpaOperator, HpaWatch};
+use crate::leader::LeaderElector;
 use crate::scale::Scale;
-use crate::util::{key, LABEL_SELECTOR, RECONCILIATION_PERIOD};
+use crate::util::{key, next_requeue_secs, patch_degraded, patch_status, LABEL_SELECTOR, LEADER_ELECTION, LEASE_DURATION, LEASE_RETRY_PERIOD, RECONCILIATION_PERIOD, CREATE_REQUEUE_SECS};
 use log::{error, info, LevelFilter};
 
 pub mod crd;
+mod calendar;
+mod events;
 mod finalizer;
+mod health;
 mod hpa;
+mod leader;
+mod metrics;
+mod server;
 mod util;
+mod webhook;
 mod scale;
 
 #[tokio::main]
@@ -34,15 +48,22 @@ async fn main() {
         })
         .filter(None, LevelFilter::Info)
         .init();
+    // the lease has to outlive a retry period, else it expires between two renewals
+    assert!(!*LEADER_ELECTION || *LEASE_DURATION > *LEASE_RETRY_PERIOD,
+            "LEASE_DURATION ({}s) must be greater than LEASE_RETRY_PERIOD ({}s)", *LEASE_DURATION, *LEASE_RETRY_PERIOD);
     // client creation
     let kubernetes_client: Client = Client::try_default()
         .await
         .expect("Expected a valid KUBECONFIG environment variable.");
 
 
+    // discover the served autoscaling api version
+    let hpa_api_resource = HpaOperator::discover(&kubernetes_client)
+        .await
+        .expect("Expected the autoscaling api group to be served.");
+
     // Preparation of resources used by the `kube_runtime::Controller`
     let crd_api: Api<ServiceScaler> = Api::all(kubernetes_client.clone());
-    let context: Arc<ContextData> = Arc::new(ContextData::new(kubernetes_client.clone()));
 
     // The controller comes from the `kube_runtime` crate and manages the reconciliation process.
     // It requires the following information:
@@ -50,7 +71,43 @@ async fn main() {
     // - `kube::runtime::watcher::Config` can be adjusted for precise filtering of `ServiceScaler` resources before the actual reconciliation, e.g. by label,
     // - `reconcile` function with reconciliation logic to be called each time a resource of `ServiceScaler` kind is created/updated/deleted,
     // - `on_error` function to call whenever reconciliation fails.
-    Controller::new(crd_api.clone(), Config::default().labels(LABEL_SELECTOR.as_str()))
+    // status-only (our own status patches) and metadata-only updates do not change the generation and are not
+    // reconciled, requeues and hpa/calendar changes still are
+    let (service_scaler_store, service_scaler_writer) = reflector::store();
+    let service_scaler_stream = reflector(service_scaler_writer, watcher(crd_api.clone(), Config::default().labels(LABEL_SELECTOR.as_str())))
+        .default_backoff()
+        .applied_objects()
+        .predicate_filter(generation_and_uid);
+    let controller = Controller::for_stream(service_scaler_stream, service_scaler_store.clone());
+    let hpa_store = service_scaler_store.clone();
+    let health = Arc::new(Health::new(service_scaler_store.clone()));
+    let context: Arc<ContextData> = Arc::new(ContextData::new(kubernetes_client.clone(), hpa_api_resource, health.clone()));
+
+    // `/metrics`, `/healthz`, `/readyz` and the validating webhook are served by every replica, leader or not
+    tokio::spawn(server::serve(health.clone()));
+    tokio::spawn(webhook::serve());
+    let sync_health = health.clone();
+    tokio::spawn(async move { sync_health.wait_for_sync().await });
+    // series of ServiceScalers gone from the cache are dropped every reconciliation period
+    let metrics_store = service_scaler_store.clone();
+    tokio::spawn(async move {
+        if metrics_store.wait_until_ready().await.is_err() {
+            return;
+        }
+        loop {
+            metrics::prune(&metrics_store);
+            tokio::time::sleep(Duration::from_secs(RECONCILIATION_PERIOD)).await;
+        }
+    });
+    // `ScalingCalendar` changes requeue every `ServiceScaler` referencing the calendar
+    let calendar_api: Api<ScalingCalendar> = Api::all(kubernetes_client.clone());
+    // hpa edits, deletions and kill switch flips requeue the `ServiceScaler` of the same name, status/metadata only
+    // updates and our own applies do not
+    let hpa_api: Api<DynamicObject> = Api::all_with(kubernetes_client.clone(), &context.hpa_api_resource);
+    let hpa_watch = HpaWatch::default();
+    let controller = controller
+        .watches(calendar_api, Config::default(), move |calendar| calendar::referencing_scalers(&service_scaler_store, &calendar))
+        .watches_with(hpa_api, context.hpa_api_resource.clone(), Config::default(), move |hpa| hpa::managing_scaler(&hpa_store, &hpa_watch, &hpa))
         .run(reconcile, on_error, context)
         .for_each(|reconciliation_result| async move {
             match reconciliation_result {
@@ -61,14 +118,43 @@ async fn main() {
                     error!("Reconciliation error: {:?}", reconciliation_err)
                 }
             }
-        })
-        .await;
+        });
+
+    if !*LEADER_ELECTION {
+        health.lead();
+        controller.await;
+        return;
+    }
+    // only the leader runs the controller, leadership is never handed back: the process exits and restarts as a
+    // follower so that no reconciliation outlives the lease
+    let leader_elector = LeaderElector::new(kubernetes_client.clone());
+    leader_elector.acquire().await;
+    health.lead();
+    tokio::select! {
+        _ = controller => {}
+        _ = leader_elector.hold() => {
+            error!("lost leadership! exiting!");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// generation of the ServiceScaler hashed along with its uid: the predicate filter remembers objects by name only, a
+/// ServiceScaler deleted and recreated under the same name starts over at generation 1 and still has to be reconciled
+fn generation_and_uid(service_scaler: &ServiceScaler) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    (service_scaler.meta().uid.as_ref()?, service_scaler.meta().generation?).hash(&mut hasher);
+    Some(hasher.finish())
 }
 
 /// Context injected with each `reconcile` and `on_error` method invocation.
 struct ContextData {
     /// Kubernetes client to make Kubernetes API requests with. Required for K8S resource management.
     client: Client,
+    /// HorizontalPodAutoscaler api served by the cluster, discovered at startup.
+    hpa_api_resource: ApiResource,
+    /// Liveness/readiness state served on `/healthz` and `/readyz`.
+    health: Arc<Health>,
 }
 
 impl ContextData {
@@ -77,8 +163,10 @@ impl ContextData {
     /// # Arguments:
     /// - `client`: A Kubernetes client to make Kubernetes REST API requests with. Resources
     /// will be created and deleted with this client.
-    pub fn new(client: Client) -> Self {
-        ContextData { client }
+    /// - `hpa_api_resource`: The HorizontalPodAutoscaler api served by the cluster.
+    /// - `health`: Liveness/readiness state, every reconcile is recorded on it.
+    pub fn new(client: Client, hpa_api_resource: ApiResource, health: Arc<Health>) -> Self {
+        ContextData { client, hpa_api_resource, health }
     }
 }
 
@@ -89,6 +177,20 @@ enum ServiceScalerAction {
     Delete,
 }
 
+impl ServiceScalerAction {
+    /// `action` label of the reconcile metrics
+    fn label(&self) -> &'static str {
+        match self {
+            ServiceScalerAction::Create => "create",
+            ServiceScalerAction::Update => "update",
+            ServiceScalerAction::Delete => "delete",
+        }
+    }
+}
+
+/// a ServiceScaler is created until its status names the created hpa (`hpaName`) or it carries a finalizer, the
+/// finalizer is only added when the hpa is not garbage collected through its owner reference, a status written for
+/// any other reason (Ex: a failure) does not count
 fn classify_action(service_scaler: &ServiceScaler) -> ServiceScalerAction {
     if service_scaler.meta().deletion_timestamp.is_some() {
         ServiceScalerAction::Delete
@@ -96,6 +198,7 @@ fn classify_action(service_scaler: &ServiceScaler) -> ServiceScalerAction {
         .finalizers
         .as_ref()
         .map_or(true, |finalizers| finalizers.is_empty())
+        && service_scaler.status.as_ref().and_then(|status| status.hpa_name.as_ref()).is_none()
     {
         ServiceScalerAction::Create
     } else {
@@ -105,6 +208,7 @@ fn classify_action(service_scaler: &ServiceScaler) -> ServiceScalerAction {
 
 async fn reconcile(service_scaler: Arc<ServiceScaler>, context: Arc<ContextData>) -> Result<Action, Error> {
     let client: Client = context.client.clone();
+    context.health.reconciled();
     let namespace: String = match service_scaler.namespace() {
         None => {
             // If there is no namespace defined, reconciliation ends with an error immediately.
@@ -116,36 +220,62 @@ async fn reconcile(service_scaler: Arc<ServiceScaler>, context: Arc<ContextData>
         Some(namespace) => namespace,
     };
     let name = service_scaler.name_any();
-    let hpa_operator = HpaOperator { client: client.clone() };
+    let hpa_operator = HpaOperator { client: client.clone(), api_resource: context.hpa_api_resource.clone() };
     let scale_operator = Scale { hpa_operator: hpa_operator.clone() };
-    match classify_action(&service_scaler) {
+    let action = classify_action(&service_scaler);
+    metrics::RECONCILE_TOTAL.with_label_values(&[&namespace, &name, action.label()]).inc();
+    let _timer = metrics::RECONCILE_DURATION_SECONDS.with_label_values(&[&namespace, &name]).start_timer();
+    match action {
         ServiceScalerAction::Create => {
-            finalizer::add(client.clone(), &namespace, &name).await?;
-            info!("[{}] added finalizers!", key(&namespace, &name));
-            hpa_operator.create(&namespace, &name, &service_scaler.spec.hpa, service_scaler.meta()).await?;
+            let (hpa, created) = hpa_operator.create(&namespace, &name, &service_scaler.spec.hpa, &service_scaler.spec.scale_target_ref, service_scaler.spec.adopt_existing_hpa.unwrap_or(false), service_scaler.meta()).await?;
+            if !is_controlled_by(&hpa, service_scaler.meta()) {
+                // hpas not owned by the ServiceScaler are cleaned up through the finalizer
+                finalizer::add(client.clone(), &namespace, &name).await?;
+                info!("[{}] added finalizers!", key(&namespace, &name));
+            }
+            let (reason, prefix) = if created { (REASON_HPA_CREATED, "hpa") } else { (REASON_HPA_ADOPTED, "existing hpa") };
+            events::publish(client.clone(), &service_scaler, EventType::Normal, reason,
+                format!("{} {} minReplicas:{} maxReplicas:{}", prefix, hpa.metadata.name.clone().unwrap_or_default(), hpa.spec.as_ref().and_then(|spec| spec.min_replicas).unwrap_or(1), hpa.spec.as_ref().map_or(0, |spec| spec.max_replicas))).await;
+            patch_status(client.clone(), &namespace, &name, "create", ServiceScalerStatus {
+                hpa_name: hpa.metadata.name.clone(),
+                last_known_config: service_scaler.spec.hpa.clone().into(),
+                ..Default::default()
+            }).await?;
             info!("[{}] Reconciled object! action: {}",  key(&namespace, &name), "CREATE");
-            Ok(Action::requeue(Duration::from_secs(RECONCILIATION_PERIOD)))
+            // the status and finalizer patches above do not change the generation, the update applying the active
+            // time range follows right away instead of a reconciliation period later
+            Ok(Action::requeue(Duration::from_secs(CREATE_REQUEUE_SECS)))
         }
         ServiceScalerAction::Delete => {
             hpa_operator.delete(&namespace, &name).await;
             finalizer::delete(client, &namespace, &name).await?;
             info!("[{}] deleted finalizers!", key(&namespace, &name));
             info!("[{}] Reconciled object! action: {}",  key(&namespace, &name), "DELETE");
+            metrics::forget(&namespace, &name);
             // Makes no sense to delete after a successful delete, as the resource is gone
             Ok(Action::await_change())
         }
         ServiceScalerAction::Update => {
-            let scale_op = scale_operator.act(&namespace, &name, &service_scaler).await;
+            // calendars are fetched once, the same time ranges drive the hpa and the requeue
+            let calendar_time_ranges = calendar::time_ranges(client.clone(), &service_scaler).await;
+            let time_range_spec = service_scaler.spec.effective_time_range_spec(&calendar_time_ranges);
+            let scale_op = scale_operator.act(&namespace, &name, &service_scaler, &time_range_spec).await;
+            // requeue at the next boundary or ramp step instead of waiting for the whole period
+            let requeue_secs = next_requeue_secs(&time_range_spec, Local::now().fixed_offset()).unwrap_or(RECONCILIATION_PERIOD);
             match scale_op {
                 Ok(_scale_op) => {
                     info!("[{}] Reconciled object! action: {}",  key(&namespace, &name), "UPDATE/NO-OP");
                 }
                 Err(e) => {
+                    metrics::RECONCILE_ERRORS_TOTAL.with_label_values(&[&namespace, &name, action.label()]).inc();
                     error!("[{}] Reconciled object! action: {} err: {:?}",  key(&namespace, &name), "UPDATE/NO-OP", e);
+                    let (reason, message) = e.reason();
+                    events::publish(client.clone(), &service_scaler, EventType::Warning, reason, message.clone()).await;
+                    patch_degraded(client.clone(), &namespace, &name, reason, &message).await?;
                 }
             }
-
-            Ok(Action::requeue(Duration::from_secs(RECONCILIATION_PERIOD)))
+            info!("[{}] requeueing in {}s", key(&namespace, &name), requeue_secs);
+            Ok(Action::requeue(Duration::from_secs(requeue_secs)))
         }
     }
 }
@@ -157,9 +287,26 @@ async fn reconcile(service_scaler: Arc<ServiceScaler>, context: Arc<ContextData>
 /// # Arguments
 /// - `ServiceScaler`: The erroneous resource.
 /// - `error`: A reference to the `kube::Error` that occurred during reconciliation.
-/// - `_context`: Unused argument. Context Data "injected" automatically by kube-rs.
-fn on_error(service_scaler: Arc<ServiceScaler>, error: &Error, _context: Arc<ContextData>) -> Action {
+/// - `context`: Context Data "injected" automatically by kube-rs, its client marks the resource degraded.
+fn on_error(service_scaler: Arc<ServiceScaler>, error: &Error, context: Arc<ContextData>) -> Action {
     error!("Reconciliation error:\n{:?}.\n{:?}", error, service_scaler);
+    let action = classify_action(&service_scaler);
+    metrics::RECONCILE_ERRORS_TOTAL.with_label_values(&[&service_scaler.namespace().unwrap_or_default(), &service_scaler.name_any(), action.label()]).inc();
+    // surface the error on the object, deleted objects are left alone and the status is only written once the create
+    // succeeded (a failed create is retried as a create)
+    if let (Some(namespace), ServiceScalerAction::Create | ServiceScalerAction::Update) = (service_scaler.namespace(), &action) {
+        let (name, (reason, message)) = (service_scaler.name_any(), error.reason());
+        let created = matches!(action, ServiceScalerAction::Update);
+        tokio::spawn(async move {
+            events::publish(context.client.clone(), &service_scaler, EventType::Warning, reason, message.clone()).await;
+            if !created {
+                return;
+            }
+            if let Err(e) = patch_degraded(context.client.clone(), &namespace, &name, reason, &message).await {
+                error!("[{}] unable to mark degraded! err: {:?}", key(&namespace, &name), e);
+            }
+        });
+    }
     Action::requeue(Duration::from_secs(RECONCILIATION_PERIOD))
 }
 
@@ -176,4 +323,41 @@ pub enum Error {
     /// Error in user input or ServiceScaler resource definition, typically missing fields.
     #[error("Invalid ServiceScaler CRD: {0}")]
     UserInputError(String),
-}
\ No newline at end of file
+}
+
+impl Error {
+    /// reason and message surfaced on the ServiceScaler (Degraded condition and Warning event), field ownership
+    /// conflicts name the conflicting field managers
+    fn reason(&self) -> (&'static str, String) {
+        match self {
+            Error::KubeError { source } => match conflicting_managers(source) {
+                Some(managers) => (REASON_FIELD_CONFLICT, format!("hpa fields owned by {} (set FORCE_CONFLICTS=true to take ownership): {}", managers.join(", "), source)),
+                None => (REASON_RECONCILE_FAILED, self.to_string()),
+            },
+            _ => (REASON_RECONCILE_FAILED, self.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn service_scaler(uid: &str, generation: i64) -> ServiceScaler {
+        serde_json::from_value(json!({
+            "apiVersion": "scaler.udaan.io/v1",
+            "kind": "ServiceScaler",
+            "metadata": {"name": "dummy", "namespace": "default", "uid": uid, "generation": generation},
+            "spec": {"hpa": {"minReplicas": 2, "maxReplicas": 10}, "timeRangeSpec": []}
+        })).unwrap()
+    }
+
+    #[test]
+    fn recreated_service_scalers_pass_the_generation_filter() {
+        assert_eq!(generation_and_uid(&service_scaler("a", 1)), generation_and_uid(&service_scaler("a", 1)));
+        assert_ne!(generation_and_uid(&service_scaler("a", 1)), generation_and_uid(&service_scaler("a", 2)));
+        // deleted and recreated under the same name
+        assert_ne!(generation_and_uid(&service_scaler("a", 1)), generation_and_uid(&service_scaler("b", 1)));
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashSet;
+use kube::Error;
+use kube::runtime::reflector::{ObjectRef, Store};
+use lazy_static::lazy_static;
+use crate::crd::ServiceScaler;
+use prometheus::{register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder};
+
+// operator metrics, registered on the default registry and served on `/metrics`
+lazy_static! {
+    pub static ref RECONCILE_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "service_scaler_reconcile_total", "reconciliations by ServiceScaler and action", &["namespace", "name", "action"]
+    ).unwrap();
+    pub static ref RECONCILE_ERRORS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "service_scaler_reconcile_errors_total", "failed reconciliations by ServiceScaler and action", &["namespace", "name", "action"]
+    ).unwrap();
+    pub static ref RECONCILE_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "service_scaler_reconcile_duration_seconds", "reconciliation duration by ServiceScaler", &["namespace", "name"]
+    ).unwrap();
+    /// `bound` is one of `min`/`max`
+    pub static ref DESIRED_REPLICAS: IntGaugeVec = register_int_gauge_vec!(
+        "service_scaler_desired_replicas", "minReplicas/maxReplicas the ServiceScaler wants on the hpa", &["namespace", "name", "bound"]
+    ).unwrap();
+    /// `bound` is one of `min`/`max`
+    pub static ref ACTUAL_REPLICAS: IntGaugeVec = register_int_gauge_vec!(
+        "service_scaler_actual_replicas", "minReplicas/maxReplicas found on the hpa", &["namespace", "name", "bound"]
+    ).unwrap();
+    pub static ref ACTIVE_TIME_RANGE: IntGaugeVec = register_int_gauge_vec!(
+        "service_scaler_active_time_range", "index of the applied time range, -1 if none matches", &["namespace", "name"]
+    ).unwrap();
+    /// `bound` is one of `min`/`max`
+    pub static ref RAMP_PROGRESS: GaugeVec = register_gauge_vec!(
+        "service_scaler_ramp_progress", "fraction of the ramp towards the next boundary completed, 0 if not ramping", &["namespace", "name", "bound"]
+    ).unwrap();
+    pub static ref KILL_SWITCH: IntGaugeVec = register_int_gauge_vec!(
+        "service_scaler_kill_switch", "1 if the hpa is not managed by the ServiceScaler (kill switch flipped)", &["namespace", "name"]
+    ).unwrap();
+    pub static ref API_ERRORS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "service_scaler_api_errors_total", "failed kubernetes api calls by verb and status code", &["verb", "code"]
+    ).unwrap();
+}
+
+/// counts the failed kubernetes api call, passes the result through
+pub fn count_api_error<T>(verb: &str, res: Result<T, Error>) -> Result<T, Error> {
+    if let Err(e) = &res {
+        let code = match e {
+            Error::Api(e) => e.code.to_string(),
+            _ => "none".to_string(),
+        };
+        API_ERRORS_TOTAL.with_label_values(&[verb, &code]).inc();
+    }
+    res
+}
+
+/// drops every series of a deleted ServiceScaler
+pub fn forget(namespace: &str, name: &str) {
+    for action in ["create", "update", "delete"] {
+        let _ = RECONCILE_TOTAL.remove_label_values(&[namespace, name, action]);
+        let _ = RECONCILE_ERRORS_TOTAL.remove_label_values(&[namespace, name, action]);
+    }
+    let _ = RECONCILE_DURATION_SECONDS.remove_label_values(&[namespace, name]);
+    for bound in ["min", "max"] {
+        let _ = DESIRED_REPLICAS.remove_label_values(&[namespace, name, bound]);
+        let _ = ACTUAL_REPLICAS.remove_label_values(&[namespace, name, bound]);
+        let _ = RAMP_PROGRESS.remove_label_values(&[namespace, name, bound]);
+    }
+    let _ = ACTIVE_TIME_RANGE.remove_label_values(&[namespace, name]);
+    let _ = KILL_SWITCH.remove_label_values(&[namespace, name]);
+}
+
+/// drops every series of the ServiceScalers missing from [store], deletions without a finalizer are never reconciled
+pub fn prune(store: &Store<ServiceScaler>) {
+    let mut service_scalers = HashSet::new();
+    for family in prometheus::gather() {
+        for metric in family.get_metric() {
+            let label = |label_name: &str| metric.get_label().iter().find(|label| label.get_name() == label_name).map(|label| label.get_value().to_string());
+            if let (Some(namespace), Some(name)) = (label("namespace"), label("name")) {
+                service_scalers.insert((namespace, name));
+            }
+        }
+    }
+    for (namespace, name) in service_scalers {
+        if store.get(&ObjectRef::new(&name).within(&namespace)).is_none() {
+            forget(&namespace, &name);
+        }
+    }
+}
+
+/// `/metrics` handler, prometheus text format
+pub async fn serve_metrics() -> String {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer).expect("Failed to encode metrics");
+    String::from_utf8(buffer).expect("Failed to encode metrics")
+}
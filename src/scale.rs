@@ -1,18 +1,24 @@
-use crate::crd::{HpaOverrideSpec, HpaSpec, ServiceScaler};
-use k8s_openapi::api::autoscaling::v2beta2::{HorizontalPodAutoscaler};
-use kube::{Error, Resource};
+use crate::crd::{CONDITION_DEGRADED, CONDITION_PAUSED, CONDITION_PROGRESSING, CONDITION_READY, HpaOverrideSpec, HpaSpec, ServiceScaler, ServiceScalerStatus, TimeRangeSpec};
+use chrono::{Local, SecondsFormat};
+use k8s_openapi::api::autoscaling::v2beta2::{HorizontalPodAutoscaler, MetricSpec};
+use kube::{Resource, ResourceExt};
 use kube::error::DiscoveryError;
+use kube::runtime::events::EventType;
 use log::info;
-use crate::hpa::HpaOperator;
-use crate::util::{key, patch_status, SERVICE_SCALER_MANAGED_ANNOTATION, step, timestamp_match};
+use crate::{events, metrics, Error};
+use crate::events::{REASON_HPA_RECREATED, REASON_KILL_SWITCH, REASON_RAMP_STEP, REASON_SCALED, REASON_TIME_RANGE_ENTERED, REASON_TIME_RANGE_EXITED};
+use crate::hpa::{behavior_matches, custom_metric_specs, desired_metrics, desired_owner_reference, is_owned_metric, metric_matches, metrics_to_apply, HpaOperator};
+use crate::util::{key, limit_step, merge_behavior, merge_metrics, next_transition, patch_status, ramp_progress, ramp_step_label, SERVICE_SCALER_MANAGED_ANNOTATION, step, active_time_range, matched_time_ranges};
 
 pub struct Scale {
     pub(crate) hpa_operator: HpaOperator,
 }
 
 impl Scale {
-    fn early_exit(&self, hpa: &HorizontalPodAutoscaler, target_hpa_spec: &HpaOverrideSpec) -> bool {
-        // kill switch
+    /// whether the managed annotation on the hpa disables the ServiceScaler
+    fn kill_switch(&self, hpa: &HorizontalPodAutoscaler) -> bool {
+        let (namespace, name) = (hpa.namespace().unwrap_or_default(), hpa.name_any());
+        metrics::KILL_SWITCH.with_label_values(&[&namespace, &name]).set(0);
         if hpa.metadata.annotations.is_some() {
             let kill_switch = match hpa.metadata.annotations.clone().unwrap().get(SERVICE_SCALER_MANAGED_ANNOTATION) {
                 Some(kill_switch_value) => {
@@ -23,14 +29,37 @@ impl Scale {
                 }
             };
             if kill_switch {
+                metrics::KILL_SWITCH.with_label_values(&[&namespace, &name]).set(1);
                 return kill_switch;
             }
         }
+        false
+    }
+
+    fn early_exit(&self, hpa: &HorizontalPodAutoscaler, target_hpa_spec: &HpaOverrideSpec, desired_custom_metrics: &[MetricSpec], owned_metrics: &[MetricSpec]) -> bool {
+        // kill switch
+        if self.kill_switch(hpa) {
+            return true;
+        }
 
         // current == desired
-        let metrics = hpa.spec.clone().unwrap().metrics.unwrap();
-        let cpu_metric = metrics.iter().filter(|metric| metric.resource.clone().unwrap().name == "cpu").last();
-        let mem_metric = metrics.iter().filter(|metric| metric.resource.clone().unwrap().name == "memory").last();
+        let min_replicas_equivalence = hpa.spec.clone().unwrap().min_replicas == target_hpa_spec.min_replicas;
+        let max_replicas_equivalence = hpa.spec.clone().unwrap().max_replicas == target_hpa_spec.max_replicas.unwrap();
+        let behavior_equivalence = behavior_matches(&hpa.spec.clone().unwrap().behavior, &target_hpa_spec.behavior);
+
+        min_replicas_equivalence && max_replicas_equivalence && self.metrics_match(hpa, target_hpa_spec, desired_custom_metrics, owned_metrics) && behavior_equivalence
+    }
+
+    /// whether the metrics managed by the ServiceScaler (cpu/memory utilization and [owned_metrics]) match the desired
+    /// ones ([desired_custom_metrics] being the custom metrics of [target_hpa_spec]), foreign metrics are not compared
+    fn metrics_match(&self, hpa: &HorizontalPodAutoscaler, target_hpa_spec: &HpaOverrideSpec, desired_custom_metrics: &[MetricSpec], owned_metrics: &[MetricSpec]) -> bool {
+        let metrics = hpa.spec.clone().unwrap().metrics.unwrap_or_default();
+        let cpu_metric = metrics.iter().rfind(|metric| metric.resource.as_ref().is_some_and(|resource| resource.name == "cpu"));
+        let mem_metric = metrics.iter().rfind(|metric| metric.resource.as_ref().is_some_and(|resource| resource.name == "memory"));
+        // foreign metrics are not compared
+        let current_custom_metrics: Vec<&MetricSpec> = metrics.iter()
+            .filter(|metric| metric.type_ != "Resource" && is_owned_metric(metric, owned_metrics))
+            .collect();
 
         let cpu_util = if cpu_metric.is_some() {
             cpu_metric.unwrap().resource.clone().unwrap().target.average_utilization
@@ -43,20 +72,45 @@ impl Scale {
             None
         };
 
-        let min_replicas_equivalence = hpa.spec.clone().unwrap().min_replicas == target_hpa_spec.min_replicas;
-        let max_replicas_equivalence = hpa.spec.clone().unwrap().max_replicas == target_hpa_spec.max_replicas.unwrap();
         let target_cpu_util_equivalence = cpu_util == target_hpa_spec.target_cpu_utilization;
         let target_mem_util_equivalence = mem_util == target_hpa_spec.target_memory_utilization;
+        let custom_metrics_equivalence = current_custom_metrics.len() == desired_custom_metrics.len()
+            && desired_custom_metrics.iter().all(|metric| current_custom_metrics.iter().any(|current| metric_matches(current, metric)));
 
-        min_replicas_equivalence && max_replicas_equivalence && target_cpu_util_equivalence && target_mem_util_equivalence
+        target_cpu_util_equivalence && target_mem_util_equivalence && custom_metrics_equivalence
     }
 
 
-    pub async fn act(&self, namespace: &str, name: &str, service_scaler: &ServiceScaler) -> Result<HorizontalPodAutoscaler, Error> {
+    /// publishes time range enter/exit and kill switch events, compared against the status of the previous reconcile
+    async fn publish_transitions(&self, service_scaler: &ServiceScaler, status: &ServiceScalerStatus, paused: bool, curr: (i32, i32), desired: (i32, i32)) {
+        let client = self.hpa_operator.client.clone();
+        let previous = service_scaler.status.clone().unwrap_or_default();
+        if previous.active_time_range_name != status.active_time_range_name {
+            let replicas = format!("minReplicas:{}->{} maxReplicas:{}->{}", curr.0, desired.0, curr.1, desired.1);
+            if let Some(previous_range) = &previous.active_time_range_name {
+                events::publish(client.clone(), service_scaler, EventType::Normal, REASON_TIME_RANGE_EXITED,
+                    format!("exited range:{} now:{} {}", previous_range, status.active_time_range_name.as_deref().unwrap_or("default"), replicas)).await;
+            }
+            if let Some(range) = &status.active_time_range_name {
+                events::publish(client.clone(), service_scaler, EventType::Normal, REASON_TIME_RANGE_ENTERED,
+                    format!("entered range:{} previous:{} {}", range, previous.active_time_range_name.as_deref().unwrap_or("default"), replicas)).await;
+            }
+        }
+        let was_paused = previous.conditions.iter().flatten()
+            .any(|condition| condition.type_ == CONDITION_PAUSED && condition.status == "True");
+        if paused && !was_paused {
+            events::publish(client, service_scaler, EventType::Warning, REASON_KILL_SWITCH,
+                format!("hpa annotation {} is not true, minReplicas:{} maxReplicas:{} left as is", SERVICE_SCALER_MANAGED_ANNOTATION, curr.0, curr.1)).await;
+        }
+    }
+
+    /// moves the hpa towards the desired spec, [time_range_spec] are the effective time ranges (calendar entries
+    /// included) of the ServiceScaler
+    pub async fn act(&self, namespace: &str, name: &str, service_scaler: &ServiceScaler, time_range_spec: &[TimeRangeSpec]) -> Result<HorizontalPodAutoscaler, Error> {
         if service_scaler.spec.hpa.min_replicas == service_scaler.spec.hpa.max_replicas {
             info!("[{}] minReplicas==maxReplicas detected! deleting hpa!", key(namespace, name));
             self.hpa_operator.delete(namespace, name).await;
-            return Err(Error::Discovery(DiscoveryError::MissingKind("minReplicas == maxReplicas".to_string())));
+            return Err(kube::Error::Discovery(DiscoveryError::MissingKind("minReplicas == maxReplicas".to_string())).into());
         }
         // get current hpa
         let hpa = match self.hpa_operator.get(namespace, name).await {
@@ -64,20 +118,27 @@ impl Scale {
             Err(_) => {
                 // someone directly deletes hpa, create it back
                 info!("[{}] accidental hpa deletion detected! recreating hpa with default spec!", key(namespace, name));
-                // assuming create does not break
-                self.hpa_operator.create(namespace, name, &service_scaler.spec.hpa, service_scaler.meta()).await.unwrap()
+                // a failed create is surfaced by the caller like any other failure
+                let hpa = self.hpa_operator.create(namespace, name, &service_scaler.spec.hpa, &service_scaler.spec.scale_target_ref, service_scaler.spec.adopt_existing_hpa.unwrap_or(false), service_scaler.meta()).await?.0;
+                events::publish(self.hpa_operator.client.clone(), service_scaler, EventType::Warning, REASON_HPA_RECREATED,
+                    format!("hpa deleted outside the ServiceScaler, recreated with minReplicas:{} maxReplicas:{}", service_scaler.spec.hpa.min_replicas, service_scaler.spec.hpa.max_replicas)).await;
+                hpa
             }
         };
 
         // get override spec
-        let range_match = service_scaler.spec.time_range_spec.iter().filter(|range_spec| {
-            timestamp_match(&range_spec.from, &range_spec.to, &range_spec.kind)
-        }).last();
+        // every time range is evaluated against the same instant
+        let curr_ts = Local::now().fixed_offset();
+        let matched_time_ranges = matched_time_ranges(time_range_spec, curr_ts)?;
+        // overlapping ranges: highest priority wins, ties go to the range declared last
+        let range_match_index = active_time_range(time_range_spec, &matched_time_ranges);
+        let range_match = range_match_index.map(|index| &time_range_spec[index]);
+        metrics::ACTIVE_TIME_RANGE.with_label_values(&[namespace, name]).set(range_match_index.map_or(-1, |index| index as i64));
 
         let default_hpa_spec = service_scaler.clone().spec.hpa;
         let mut hpa_override_spec = match range_match {
             Some(range_match) => {
-                info!("[{}] from_ts:{} to_ts:{} ts_match:{}", key(namespace, name), range_match.from, range_match.to, "true");
+                info!("[{}] range:{} from_ts:{} to_ts:{} priority:{} ts_match:{} matched_ranges:{:?}", key(namespace, name), range_match.label(), range_match.from, range_match.to, range_match.priority.unwrap_or(0), "true", matched_time_ranges);
                 range_match.clone().replica_spec.hpa
             }
             None => {
@@ -87,9 +148,15 @@ impl Scale {
                     max_replicas: Some(default_hpa_spec.max_replicas),
                     target_cpu_utilization: default_hpa_spec.target_cpu_utilization,
                     target_memory_utilization: default_hpa_spec.target_memory_utilization,
+                    metrics: None,
+                    behavior: None,
                 }
             }
         };
+        // custom metrics: overrides replace the default target of the same metric
+        hpa_override_spec.metrics = merge_metrics(&default_hpa_spec.metrics, &hpa_override_spec.metrics);
+        // behavior: overrides replace the default scaleUp/scaleDown rules
+        hpa_override_spec.behavior = merge_behavior(&default_hpa_spec.behavior, &hpa_override_spec.behavior);
 
         // prepare final [HpaSpec] patch
 
@@ -98,7 +165,7 @@ impl Scale {
         if hpa_override_spec.min_replicas.is_none() {
             hpa_override_spec.min_replicas = Some(default_hpa_spec.max_replicas)
         }
-        hpa_override_spec.min_replicas = Some(step(curr_min_replicas, default_hpa_spec.min_replicas, hpa_override_spec.min_replicas.unwrap(), &service_scaler.spec.time_range_spec, false).unwrap());
+        hpa_override_spec.min_replicas = Some(step(default_hpa_spec.min_replicas, hpa_override_spec.min_replicas.unwrap(), time_range_spec, false, curr_ts)?);
         info!("[{}] minReplicas - from:{} to:{}", key(namespace, name), curr_min_replicas, hpa_override_spec.min_replicas.unwrap());
 
         //maxReplicas step shenanigans
@@ -106,8 +173,43 @@ impl Scale {
         if hpa_override_spec.max_replicas.is_none() {
             hpa_override_spec.max_replicas = Some(default_hpa_spec.max_replicas)
         }
-        hpa_override_spec.max_replicas = Some(step(curr_max_replicas, default_hpa_spec.max_replicas, hpa_override_spec.max_replicas.unwrap(), &service_scaler.spec.time_range_spec, true).unwrap());
+        hpa_override_spec.max_replicas = Some(step(default_hpa_spec.max_replicas, hpa_override_spec.max_replicas.unwrap(), time_range_spec, true, curr_ts)?);
         info!("[{}] maxReplicas - from:{} to:{}", key(namespace, name), curr_max_replicas, hpa_override_spec.max_replicas.unwrap());
+
+        // scale-up/down policies
+        let status = service_scaler.status.clone().unwrap_or_default();
+        let mut last_scale_up_time = status.last_scale_up_time;
+        let mut last_scale_down_time = status.last_scale_down_time;
+        for (curr, desired) in [(curr_min_replicas, &mut hpa_override_spec.min_replicas), (curr_max_replicas, &mut hpa_override_spec.max_replicas)] {
+            *desired = Some(if desired.unwrap() > curr {
+                limit_step(curr, desired.unwrap(), &service_scaler.spec.scale_up, &last_scale_up_time, curr_ts)?
+            } else {
+                limit_step(curr, desired.unwrap(), &service_scaler.spec.scale_down, &last_scale_down_time, curr_ts)?
+            });
+        }
+        if hpa_override_spec.min_replicas.unwrap() > curr_min_replicas || hpa_override_spec.max_replicas.unwrap() > curr_max_replicas {
+            last_scale_up_time = Some(curr_ts.to_rfc3339());
+        }
+        if hpa_override_spec.min_replicas.unwrap() < curr_min_replicas || hpa_override_spec.max_replicas.unwrap() < curr_max_replicas {
+            last_scale_down_time = Some(curr_ts.to_rfc3339());
+        }
+        info!("[{}] after scale policies - minReplicas:{} maxReplicas:{}", key(namespace, name), hpa_override_spec.min_replicas.unwrap(), hpa_override_spec.max_replicas.unwrap());
+        let mut ramping = false;
+        // minReplicas and maxReplicas may ramp towards different boundaries
+        let (min_ramp_step, max_ramp_step) = (
+            ramp_progress(default_hpa_spec.min_replicas, time_range_spec, false, curr_ts)?,
+            ramp_progress(default_hpa_spec.max_replicas, time_range_spec, true, curr_ts)?,
+        );
+        for (bound, curr, desired, ramp_step) in [
+            ("min", curr_min_replicas, hpa_override_spec.min_replicas.unwrap(), min_ramp_step),
+            ("max", curr_max_replicas, hpa_override_spec.max_replicas.unwrap(), max_ramp_step),
+        ] {
+            metrics::ACTUAL_REPLICAS.with_label_values(&[namespace, name, bound]).set(curr as i64);
+            metrics::DESIRED_REPLICAS.with_label_values(&[namespace, name, bound]).set(desired as i64);
+            let ramp_progress = ramp_step.map_or(0f64, |(step, steps)| step as f64 / steps as f64);
+            ramping |= ramp_progress > 0f64 && desired != curr;
+            metrics::RAMP_PROGRESS.with_label_values(&[namespace, name, bound]).set(ramp_progress);
+        }
         // targetCPUUtil
         if hpa_override_spec.target_cpu_utilization.is_none() && default_hpa_spec.target_cpu_utilization.is_some() {
             hpa_override_spec.target_cpu_utilization = default_hpa_spec.target_cpu_utilization
@@ -124,20 +226,92 @@ impl Scale {
             hpa_override_spec.target_memory_utilization = None
         }
 
+        // metrics managed by the ServiceScaler: the defaults and every override, anything else on the hpa is foreign
+        let owned_metrics = custom_metric_specs(&Some(
+            service_scaler.spec.hpa.metrics.iter().flatten()
+                .chain(time_range_spec.iter().flat_map(|time_range| time_range.replica_spec.hpa.metrics.iter().flatten()))
+                .cloned()
+                .collect()
+        ))?;
+        let desired_custom_metrics = custom_metric_specs(&hpa_override_spec.metrics)?;
+
+        // what happens next, minReplicas and maxReplicas may move at different boundaries
+        let next_min = next_transition(default_hpa_spec.min_replicas, time_range_spec, false, curr_ts)?;
+        let next_max = next_transition(default_hpa_spec.max_replicas, time_range_spec, true, curr_ts)?;
+
         // early exit
-        if self.early_exit(&hpa, &hpa_override_spec) {
-            patch_status(self.hpa_operator.client.clone(), namespace, name, range_match.is_some(), "no-op", &hpa_override_spec).await.expect("patch_status errored!");
+        let mut status = ServiceScalerStatus {
+            time_range_match: range_match.is_some(),
+            matched_time_ranges: Some(matched_time_ranges.clone()),
+            hpa_name: hpa.metadata.name.clone(),
+            last_known_config: hpa_override_spec.clone(),
+            last_scale_up_time,
+            last_scale_down_time,
+            active_time_range: range_match_index,
+            active_time_range_name: range_match.map(|range_match| range_match.label()),
+            // the earliest boundary moving either bound
+            next_transition_time: next_min.iter().chain(next_max.iter()).map(|(ts, _, _)| *ts).min().map(|ts| ts.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            next_min_replicas: next_min.as_ref().map(|(_, target, _)| *target),
+            next_max_replicas: next_max.as_ref().map(|(_, target, _)| *target),
+            ramp_step: ramp_step_label(min_ramp_step, max_ramp_step),
+            observed_min_replicas: Some(curr_min_replicas),
+            observed_max_replicas: Some(curr_max_replicas),
+            conditions: service_scaler.status.as_ref().and_then(|status| status.conditions.clone()),
+            ..Default::default()
+        };
+        let paused = self.kill_switch(&hpa);
+        self.publish_transitions(service_scaler, &status, paused, (curr_min_replicas, curr_max_replicas), (hpa_override_spec.min_replicas.unwrap(), hpa_override_spec.max_replicas.unwrap())).await;
+        if paused {
+            status.set_condition(CONDITION_PAUSED, true, "KillSwitch", &format!("hpa annotation {} is not true", SERVICE_SCALER_MANAGED_ANNOTATION));
+            status.set_condition(CONDITION_READY, false, "KillSwitch", "hpa is not managed by the ServiceScaler");
+        } else {
+            status.set_condition(CONDITION_PAUSED, false, "Managed", "hpa is managed by the ServiceScaler");
+        }
+        let changed = hpa_override_spec.min_replicas.unwrap() != curr_min_replicas || hpa_override_spec.max_replicas.unwrap() != curr_max_replicas;
+        match (changed && !paused, ramping) {
+            (true, true) => status.set_condition(CONDITION_PROGRESSING, true, "Ramping", &format!("ramping minReplicas:{} maxReplicas:{} towards range:{}", hpa_override_spec.min_replicas.unwrap(), hpa_override_spec.max_replicas.unwrap(), next_min.as_ref().or(next_max.as_ref()).map_or("-", |(_, _, range)| range.as_str()))),
+            (true, false) => status.set_condition(CONDITION_PROGRESSING, true, "Scaling", &format!("moving to minReplicas:{} maxReplicas:{} for range:{}", hpa_override_spec.min_replicas.unwrap(), hpa_override_spec.max_replicas.unwrap(), range_match.map_or("-".to_string(), |range_match| range_match.label()))),
+            _ => status.set_condition(CONDITION_PROGRESSING, false, "Stable", "hpa matches the desired replicas"),
+        }
+        if self.early_exit(&hpa, &hpa_override_spec, &desired_custom_metrics, &owned_metrics) {
+            if !paused {
+                status.set_condition(CONDITION_READY, true, "Reconciled", "hpa matches the desired spec");
+            }
+            status.set_condition(CONDITION_DEGRADED, false, "AsExpected", "");
+            patch_status(self.hpa_operator.client.clone(), namespace, name, "no-op", status).await?;
             info!("[{}] early-exit no-op!", key(namespace, name));
             return Ok(hpa);
         }
 
-        let res = self.hpa_operator.patch(namespace, name, &HpaSpec {
+        let owner_reference = desired_owner_reference(&hpa, service_scaler.meta(), service_scaler.spec.adopt_existing_hpa.unwrap_or(false));
+        let hpa_spec = HpaSpec {
             min_replicas: hpa_override_spec.min_replicas.unwrap(),
             max_replicas: hpa_override_spec.max_replicas.unwrap(),
             target_cpu_utilization: hpa_override_spec.target_cpu_utilization,
             target_memory_utilization: hpa_override_spec.target_memory_utilization,
-        }).await;
-        patch_status(self.hpa_operator.client.clone(), namespace, name, range_match.is_some(), "patch", &hpa_override_spec).await.expect("patch_status errored!");
-        res
+            metrics: hpa_override_spec.metrics.clone(),
+            behavior: hpa_override_spec.behavior.clone(),
+        };
+        // the metrics list found on the hpa is re-applied as is unless a managed metric changes, see [metrics_to_apply]
+        let live_metrics = self.hpa_operator.live_metrics(namespace, name).await?;
+        let metrics = metrics_to_apply(desired_metrics(namespace, name, &hpa_spec)?, live_metrics, &owned_metrics, self.metrics_match(&hpa, &hpa_override_spec, &desired_custom_metrics, &owned_metrics));
+        // a failed apply is surfaced by the caller (Degraded condition and Warning event), the status keeps the
+        // previous scale-up/down times as nothing moved
+        let res = self.hpa_operator.patch(namespace, name, &hpa_spec, &service_scaler.spec.scale_target_ref, metrics, owner_reference, service_scaler.meta()).await?;
+        let client = self.hpa_operator.client.clone();
+        // the hpa as applied, not as found before the apply
+        status.observed_min_replicas = res.spec.as_ref().and_then(|spec| spec.min_replicas);
+        status.observed_max_replicas = res.spec.as_ref().map(|spec| spec.max_replicas);
+        status.set_condition(CONDITION_READY, true, "Reconciled", "hpa matches the desired spec");
+        status.set_condition(CONDITION_DEGRADED, false, "AsExpected", "");
+        if changed {
+            let replicas = format!("minReplicas:{}->{} maxReplicas:{}->{}", curr_min_replicas, hpa_override_spec.min_replicas.unwrap(), curr_max_replicas, hpa_override_spec.max_replicas.unwrap());
+            match &status.ramp_step {
+                Some(ramp_step) if ramping => events::publish(client, service_scaler, EventType::Normal, REASON_RAMP_STEP, format!("{} step {}", replicas, ramp_step)).await,
+                _ => events::publish(client, service_scaler, EventType::Normal, REASON_SCALED, replicas).await,
+            }
+        }
+        patch_status(self.hpa_operator.client.clone(), namespace, name, "patch", status).await?;
+        Ok(res)
     }
 }
\ No newline at end of file
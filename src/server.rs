@@ -0,0 +1,43 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use log::info;
+use crate::health::Health;
+use crate::metrics;
+use crate::util::HTTP_PORT;
+
+/// `/healthz` handler, liveness probe
+async fn healthz(State(health): State<Arc<Health>>) -> (StatusCode, &'static str) {
+    if health.live() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "no reconcile within the liveness deadline")
+    }
+}
+
+/// `/readyz` handler, readiness probe
+async fn readyz(State(health): State<Arc<Health>>) -> (StatusCode, &'static str) {
+    if health.ready() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not leading or not synced")
+    }
+}
+
+/// serves the operator http endpoints on [HTTP_PORT]
+pub async fn serve(health: Arc<Health>) {
+    let router = Router::new()
+        .route("/metrics", get(metrics::serve_metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(health);
+    let addr = SocketAddr::from(([0, 0, 0, 0], *HTTP_PORT));
+    info!("serving http on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(router.into_make_service())
+        .await
+        .expect("http server errored!");
+}
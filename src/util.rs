@@ -1,152 +1,592 @@
 use std::string::ToString;
-use crate::crd::{HpaOverrideSpec, ServiceScaler, ServiceScalerStatus, TimeRangeSpec, TimeRangeType};
+use crate::crd::{CONDITION_DEGRADED, CONDITION_READY, CustomMetricSpec, DayOfWeek, HpaBehavior, RampCurve, ServiceScaler, ServiceScalerStatus, StepPolicy, TimeRangeSpec, TimeRangeType};
 use chrono::prelude::*;
 use chrono::*;
 use kube::{Api, Client, Resource};
 use kube::api::{Patch, PatchParams};
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use crate::Error;
 use std::env;
 use lazy_static::lazy_static;
+use std::str::FromStr;
+use cron::Schedule;
+use chrono_tz::Tz;
 
 // runtime constants
 lazy_static! {
     pub static ref LABEL_SELECTOR: String = env::var("LABEL_SELECTOR").unwrap_or("".to_string());
+    /// take over fields owned by other field managers on server-side apply
+    pub static ref FORCE_CONFLICTS: bool = env::var("FORCE_CONFLICTS").map(|force| force.to_lowercase() == "true").unwrap_or(false);
+    /// run the controller only while holding the lease, allows running multiple replicas
+    pub static ref LEADER_ELECTION: bool = env::var("LEADER_ELECTION").map(|enabled| enabled.to_lowercase() == "true").unwrap_or(false);
+    pub static ref LEASE_NAME: String = env::var("LEASE_NAME").unwrap_or("service-scaler-operator".to_string());
+    pub static ref LEASE_NAMESPACE: String = env::var("LEASE_NAMESPACE").or(env::var("POD_NAMESPACE")).unwrap_or("default".to_string());
+    /// in seconds, followers take over at most [LEASE_DURATION] + [LEASE_RETRY_PERIOD] after the leader stops renewing
+    pub static ref LEASE_DURATION: u64 = env::var("LEASE_DURATION").ok().and_then(|duration| duration.parse().ok()).unwrap_or(15);
+    /// in seconds
+    pub static ref LEASE_RETRY_PERIOD: u64 = env::var("LEASE_RETRY_PERIOD").ok().and_then(|period| period.parse().ok()).unwrap_or(5);
+    /// port serving `/metrics`, `/healthz` and `/readyz`
+    pub static ref HTTP_PORT: u16 = env::var("HTTP_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(8080);
+    /// in seconds, `/healthz` fails if the leader has not reconciled within this deadline
+    pub static ref LIVENESS_DEADLINE: u64 = env::var("LIVENESS_DEADLINE").ok().and_then(|deadline| deadline.parse().ok()).unwrap_or(2 * RECONCILIATION_PERIOD);
+    /// port serving the validating webhook over https
+    pub static ref WEBHOOK_PORT: u16 = env::var("WEBHOOK_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(8443);
+    pub static ref WEBHOOK_CERT: String = env::var("WEBHOOK_CERT").unwrap_or("/certs/tls.crt".to_string());
+    pub static ref WEBHOOK_KEY: String = env::var("WEBHOOK_KEY").unwrap_or("/certs/tls.key".to_string());
+    /// leader election identity
+    pub static ref POD_NAME: String = env::var("POD_NAME").or(env::var("HOSTNAME")).unwrap_or("service-scaler-operator".to_string());
 }
 
+/// field manager of every write made by the operator
+pub const FIELD_MANAGER: &str = "service-scaler";
+
 /// in seconds
 pub static RECONCILIATION_PERIOD: u64 = 300;
 
+/// in seconds, delay between the create of the hpa and the first update
+pub static CREATE_REQUEUE_SECS: u64 = 1;
+
+/// default ramp-up/down duration in reconciliation periods (30min)
+pub const DEFAULT_RAMP_INTERVALS: i32 = 6;
+
 /// labels
 pub const SERVICE_SCALER_MANAGED_ANNOTATION: &str = "service-scaler.kubernetes.io/managed";
 pub const SERVICE_SCALER_NOTE_KEY: &str = "service-scaler.kubernetes.io/note";
 pub const SERVICE_SCALER_NOTE_VALUE: &str = "DO-NOT-EDIT-THIS--EDIT-SERVICE-SCALER-INSTEAD";
 
 // kubernetes api versions
+/// version of the HorizontalPodAutoscaler structs, the served version is discovered at startup
 pub const K8S_AUTOSCALING_VERSION: &str = "autoscaling/v2beta2";
-pub const K8S_DEPLOYMENT_VERSION: &str = "apps/v2beta2";
+pub const K8S_AUTOSCALING_GROUP: &str = "autoscaling";
+pub const K8S_AUTOSCALING_V2_VERSION: &str = "v2";
+pub const K8S_AUTOSCALING_V2BETA2_VERSION: &str = "v2beta2";
+pub const K8S_DEPLOYMENT_VERSION: &str = "apps/v1";
 
 pub fn key(namespace: &str, name: &str) -> String {
     [namespace, name].join("/")
 }
 
-fn parse_zoned_time_str(ts: &str) -> DateTime<FixedOffset> {
-    let today = Local::now().format("%d-%m-%y").to_string();
-    return DateTime::parse_from_str((today + ts).as_str(), "%d-%m-%y %H:%M%:z").expect("Failed to parse from ZonedDateTime");
+/// merge patch params recording the service-scaler field manager
+pub fn patch_params() -> PatchParams {
+    PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    }
 }
 
-fn parse_zoned_date_time_str(ts: &str) -> DateTime<FixedOffset> {
-    DateTime::parse_from_rfc3339(ts).expect("Failed to parse from ZonedDateTime")
+/// parses an IANA time zone name, Ex: `Europe/London`
+fn parse_time_zone(time_zone: &Option<String>) -> Result<Option<Tz>, Error> {
+    time_zone.as_ref()
+        .map(|time_zone| time_zone.parse::<Tz>().map_err(|_| Error::UserInputError(format!("unknown time zone {:?}", time_zone))))
+        .transpose()
 }
 
-pub fn timestamp_match(from: &str, to: &str, kind: &TimeRangeType) -> bool {
-    let curr_ts = Local::now().fixed_offset();
-    match kind {
+/// maps a wall clock time in [tz] to an instant, DST overlaps resolve to the earlier instant and DST gaps resolve to
+/// the first wall clock time after the gap
+fn resolve_local(tz: &Tz, local: NaiveDateTime) -> DateTime<FixedOffset> {
+    let mut local = local;
+    loop {
+        match tz.from_local_datetime(&local) {
+            LocalResult::Single(ts) => return ts.fixed_offset(),
+            LocalResult::Ambiguous(earliest, _) => return earliest.fixed_offset(),
+            LocalResult::None => local += Duration::minutes(1),
+        }
+    }
+}
+
+/// parses `HH:MM<tz-offset>` (or `HH:MM` if a time zone is given) on the day [day_offset] days from [curr_ts]
+fn parse_zoned_time_str(ts: &str, time_zone: &Option<Tz>, day_offset: i64, curr_ts: DateTime<FixedOffset>) -> Result<DateTime<FixedOffset>, Error> {
+    match (time_zone, NaiveTime::parse_from_str(ts, "%H:%M")) {
+        (Some(tz), Ok(time)) => {
+            let date = curr_ts.with_timezone(tz).date_naive() + Duration::days(day_offset);
+            Ok(resolve_local(tz, date.and_time(time)))
+        }
+        _ => {
+            let today = curr_ts.format("%d-%m-%y").to_string();
+            DateTime::parse_from_str((today + ts).as_str(), "%d-%m-%y %H:%M%:z")
+                .map(|ts| ts + Duration::days(day_offset))
+                .map_err(|e| Error::UserInputError(format!("invalid ZonedTime {:?}: {}", ts, e)))
+        }
+    }
+}
+
+/// parses `rfc3339` (or `YYYY-MM-DDTHH:MM:SS` if a time zone is given)
+fn parse_zoned_date_time_str(ts: &str, time_zone: &Option<Tz>) -> Result<DateTime<FixedOffset>, Error> {
+    match (time_zone, NaiveDateTime::parse_from_str(ts, "%Y-%m-%dT%H:%M:%S")) {
+        (Some(tz), Ok(local)) => Ok(resolve_local(tz, local)),
+        _ => DateTime::parse_from_rfc3339(ts).map_err(|e| Error::UserInputError(format!("invalid ZonedDateTime {:?}: {}", ts, e))),
+    }
+}
+
+/// parses a cron expression of the `cron` crate: `sec min hour day-of-month month day-of-week [year]`, standard
+/// 5-field crontab expressions (`0 9 * * MON-FRI`) are accepted and pinned to second 0
+///
+/// numeric days of week follow the `cron` crate (`1` = Sunday through `7` = Saturday) and not crontab (`0`/`7` =
+/// Sunday, `1` = Monday), see [check_time_str] which rejects them in 5-field expressions
+fn parse_cron_str(expr: &str) -> Result<Schedule, Error> {
+    let expr = if expr.split_whitespace().count() == 5 {
+        format!("0 {}", expr)
+    } else {
+        expr.to_string()
+    };
+    Schedule::from_str(&expr).map_err(|e| Error::UserInputError(format!("invalid cron expression {:?}: {}", expr, e)))
+}
+
+/// most recent fire time of the cron expression at or before [ts], evaluated in [time_zone] if given
+fn last_cron_fire(expr: &str, ts: &DateTime<FixedOffset>, time_zone: &Option<Tz>) -> Result<Option<DateTime<FixedOffset>>, Error> {
+    let schedule = parse_cron_str(expr)?;
+    Ok(match time_zone {
+        Some(tz) => schedule.after(&ts.with_timezone(tz)).next_back().map(|ts| ts.fixed_offset()),
+        None => schedule.after(ts).next_back(),
+    })
+}
+
+/// next fire time of the cron expression after [ts], evaluated in [time_zone] if given
+fn next_cron_fire(expr: &str, ts: &DateTime<FixedOffset>, time_zone: &Option<Tz>) -> Result<Option<DateTime<FixedOffset>>, Error> {
+    let schedule = parse_cron_str(expr)?;
+    Ok(match time_zone {
+        Some(tz) => schedule.after(&ts.with_timezone(tz)).next().map(|ts| ts.fixed_offset()),
+        None => schedule.after(ts).next(),
+    })
+}
+
+/// whether the day [ts] falls on is allowed by the (optional) day-of-week restriction
+fn day_allowed(ts: &DateTime<FixedOffset>, days_of_week: &Option<Vec<DayOfWeek>>) -> bool {
+    match days_of_week {
+        Some(days) if !days.is_empty() => days.iter().any(|day| Weekday::from(day.clone()) == ts.weekday()),
+        _ => true,
+    }
+}
+
+/// parses a duration like `90s`, `15m` or `2h`
+fn parse_duration_str(duration: &str) -> Result<Duration, Error> {
+    let invalid = || Error::UserInputError(format!("invalid duration {:?}, expected Ex: 90s, 15m or 2h", duration));
+    if duration.is_empty() || !duration.is_char_boundary(duration.len() - 1) {
+        return Err(invalid());
+    }
+    let (value, unit) = duration.split_at(duration.len() - 1);
+    let value: i64 = value.parse().ok().filter(|value| *value >= 0).ok_or_else(invalid)?;
+    match unit {
+        "s" => Ok(Duration::seconds(value)),
+        "m" => Ok(Duration::minutes(value)),
+        "h" => Ok(Duration::hours(value)),
+        _ => Err(invalid()),
+    }
+}
+
+/// checks a duration like `90s`, `15m` or `2h`
+pub fn check_duration_str(field: &str, duration: &str) -> Result<(), String> {
+    parse_duration_str(duration).map(|_| ()).map_err(|_| format!("{}: invalid duration {:?}, expected Ex: 90s, 15m or 2h", field, duration))
+}
+
+/// checks an IANA time zone name
+pub fn check_time_zone(field: &str, time_zone: &str) -> Result<(), String> {
+    time_zone.parse::<Tz>().map(|_| ()).map_err(|_| format!("{}: unknown time zone {:?}, expected an IANA name Ex: Europe/London", field, time_zone))
+}
+
+/// checks `from`/`to` of the time range against its kind, see [parse_zoned_time_str], [parse_zoned_date_time_str] and
+/// [parse_cron_str]
+pub fn check_time_str(field: &str, kind: &TimeRangeType, ts: &str, time_zone: &Option<String>) -> Result<(), String> {
+    let valid = match kind {
         TimeRangeType::ZonedTime => {
-            let from_ts = parse_zoned_time_str(from);
-            let mut to_ts = parse_zoned_time_str(to);
-            if to_ts < from_ts {
-                to_ts += Duration::days(1);
+            (time_zone.is_some() && NaiveTime::parse_from_str(ts, "%H:%M").is_ok())
+                || DateTime::parse_from_str(format!("01-01-70{}", ts).as_str(), "%d-%m-%y %H:%M%:z").is_ok()
+        }
+        TimeRangeType::ZonedDateTime => {
+            (time_zone.is_some() && NaiveDateTime::parse_from_str(ts, "%Y-%m-%dT%H:%M:%S").is_ok())
+                || DateTime::parse_from_rfc3339(ts).is_ok()
+        }
+        TimeRangeType::Cron => {
+            // crontab numbers its days of week differently, see [parse_cron_str]
+            let fields: Vec<&str> = ts.split_whitespace().collect();
+            if fields.len() == 5 && fields[4].chars().any(|c| c.is_ascii_digit()) {
+                return Err(format!("{}: numeric day of week in {:?} is ambiguous, use names Ex: MON-FRI", field, ts));
             }
-            info!("Trying from_ts:{} curr_ts:{} to_ts:{}", from_ts, curr_ts, to_ts);
-            (curr_ts > from_ts) && (curr_ts < to_ts)
+            parse_cron_str(ts).is_ok()
+        }
+    };
+    if valid {
+        return Ok(());
+    }
+    let expected = match (kind, time_zone) {
+        (TimeRangeType::ZonedTime, Some(_)) => "HH:MM or HH:MM<tz-offset> Ex: 08:00+05:30",
+        (TimeRangeType::ZonedTime, None) => "HH:MM<tz-offset> Ex: 08:00+05:30",
+        (TimeRangeType::ZonedDateTime, Some(_)) => "YYYY-MM-DDTHH:MM:SS or rfc3339 Ex: 2023-01-11T08:00:00+05:30",
+        (TimeRangeType::ZonedDateTime, None) => "rfc3339 Ex: 2023-01-11T08:00:00+05:30",
+        (TimeRangeType::Cron, _) => "a cron expression Ex: 0 9 * * MON-FRI",
+    };
+    Err(format!("{}: invalid {:?} time {:?}, expected {}", field, kind, ts, expected))
+}
+
+/// (from, to) instants of the time range
+pub type Window = (DateTime<FixedOffset>, DateTime<FixedOffset>);
+
+/// (from, to) windows of a (valid) time range starting the day before [curr_ts] through a week after, lead/lag time
+/// excluded
+pub fn time_range_windows(time_range: &TimeRangeSpec, curr_ts: DateTime<FixedOffset>) -> Result<Vec<Window>, Error> {
+    let (from, to) = (time_range.from.as_str(), time_range.to.as_str());
+    let time_zone = parse_time_zone(&time_range.time_zone)?;
+    match time_range.kind {
+        TimeRangeType::ZonedTime => zoned_time_windows(from, to, &time_range.days_of_week, &time_zone, curr_ts),
+        TimeRangeType::ZonedDateTime => Ok(vec![(parse_zoned_date_time_str(from, &time_zone)?, parse_zoned_date_time_str(to, &time_zone)?)]),
+        TimeRangeType::Cron => {
+            // every `from` firing opens a window closed by the next `to` firing, capped so that a range firing every
+            // minute is still checked within a reasonable time
+            let mut windows = vec![];
+            let mut ts = curr_ts - Duration::days(1);
+            while let Some(from_ts) = next_cron_fire(from, &ts, &time_zone)?.filter(|from_ts| *from_ts < curr_ts + Duration::days(7) && windows.len() < 1000) {
+                match next_cron_fire(to, &from_ts, &time_zone)? {
+                    Some(to_ts) => windows.push((from_ts, to_ts)),
+                    None => break,
+                }
+                ts = from_ts;
+            }
+            Ok(windows)
+        }
+    }
+}
+
+/// checks that every field of the time range parses, Ex: a calendar entry before it is applied
+pub fn check_time_range(time_range: &TimeRangeSpec) -> Result<(), Error> {
+    time_range_windows(time_range, Local::now().fixed_offset()).map(|_| ())
+}
+
+/// (lead, lag) of the time range, the window is shifted to start [lead] before `from` and end [lag] after `to`
+fn lead_lag(time_range: &TimeRangeSpec) -> Result<(Duration, Duration), Error> {
+    Ok((
+        time_range.lead_time.as_deref().map_or(Ok(Duration::zero()), parse_duration_str)?,
+        time_range.lag_time.as_deref().map_or(Ok(Duration::zero()), parse_duration_str)?,
+    ))
+}
+
+/// (from, to) windows of a ZonedTime range starting the day before [curr_ts] through a week after, windows crossing
+/// midnight belong to the day they start on, days not allowed by [days_of_week] are skipped
+fn zoned_time_windows(from: &str, to: &str, days_of_week: &Option<Vec<DayOfWeek>>, time_zone: &Option<Tz>, curr_ts: DateTime<FixedOffset>) -> Result<Vec<Window>, Error> {
+    // every day is resolved on its own so that windows keep their wall clock times across DST transitions
+    let mut windows = vec![];
+    for offset in -1..=7 {
+        let from_ts = parse_zoned_time_str(from, time_zone, offset, curr_ts)?;
+        let mut to_ts = parse_zoned_time_str(to, time_zone, offset, curr_ts)?;
+        if to_ts < from_ts {
+            to_ts = parse_zoned_time_str(to, time_zone, offset + 1, curr_ts)?;
+        }
+        if day_allowed(&from_ts, days_of_week) {
+            windows.push((from_ts, to_ts));
+        }
+    }
+    Ok(windows)
+}
+
+/// whether [curr_ts] falls within the time range, lead/lag time applied
+pub fn timestamp_match(time_range: &TimeRangeSpec, curr_ts: DateTime<FixedOffset>) -> Result<bool, Error> {
+    let (from, to) = (time_range.from.as_str(), time_range.to.as_str());
+    let time_zone = parse_time_zone(&time_range.time_zone)?;
+    let (lead, lag) = lead_lag(time_range)?;
+    match time_range.kind {
+        TimeRangeType::ZonedTime => {
+            let window = zoned_time_windows(from, to, &time_range.days_of_week, &time_zone, curr_ts)?.into_iter().find(|(from_ts, to_ts)| {
+                (curr_ts > *from_ts - lead) && (curr_ts < *to_ts + lag)
+            });
+            debug!("Trying range:{} from:{} curr_ts:{} to:{} days_of_week:{:?} window:{:?}", time_range.label(), from, curr_ts, to, time_range.days_of_week, window);
+            Ok(window.is_some())
         }
         TimeRangeType::ZonedDateTime => {
-            let from_ts = parse_zoned_date_time_str(from);
-            let to_ts = parse_zoned_date_time_str(to);
-            (curr_ts > from_ts) && (curr_ts < to_ts)
+            let from_ts = parse_zoned_date_time_str(from, &time_zone)? - lead;
+            let to_ts = parse_zoned_date_time_str(to, &time_zone)? + lag;
+            Ok((curr_ts > from_ts) && (curr_ts < to_ts))
+        }
+        TimeRangeType::Cron => {
+            // the range is active if [from] fired more recently than [to], [from] is looked up [lead] ahead and [to]
+            // [lag] behind
+            let from_ts = last_cron_fire(from, &(curr_ts + lead), &time_zone)?;
+            let to_ts = last_cron_fire(to, &(curr_ts - lag), &time_zone)?;
+            debug!("Trying range:{} last_from_ts:{:?} curr_ts:{} last_to_ts:{:?}", time_range.label(), from_ts, curr_ts, to_ts);
+            Ok(match (from_ts, to_ts) {
+                (Some(from_ts), Some(to_ts)) => from_ts > to_ts,
+                (Some(_), None) => true,
+                _ => false,
+            })
         }
     }
 }
 
-fn diff_from_now(ts: &str, kind: &TimeRangeType) -> i64 {
-    // get current time
-    let curr_ts = Local::now().fixed_offset();
-    match kind {
+/// seconds from [curr_ts] to the next `from` (less lead time) and the next `to` (plus lag time) of the time range
+fn diff_from_now(time_range: &TimeRangeSpec, curr_ts: DateTime<FixedOffset>) -> Result<(i64, i64), Error> {
+    let (from, to) = (time_range.from.as_str(), time_range.to.as_str());
+    let time_zone = parse_time_zone(&time_range.time_zone)?;
+    let (lead, lag) = lead_lag(time_range)?;
+    match time_range.kind {
         TimeRangeType::ZonedTime => {
-            let mut ts = parse_zoned_time_str(ts);
-            if ts < curr_ts {
-                ts += Duration::days(1);
-            }
-            (ts - curr_ts).num_seconds()
+            let windows = zoned_time_windows(from, to, &time_range.days_of_week, &time_zone, curr_ts)?;
+            let next_from = windows.iter().map(|(from_ts, _)| *from_ts - lead).filter(|from_ts| *from_ts >= curr_ts).min();
+            let next_to = windows.iter().map(|(_, to_ts)| *to_ts + lag).filter(|to_ts| *to_ts >= curr_ts).min();
+            Ok((
+                next_from.map_or(i64::MAX, |ts| (ts - curr_ts).num_seconds()),
+                next_to.map_or(i64::MAX, |ts| (ts - curr_ts).num_seconds()),
+            ))
         }
         TimeRangeType::ZonedDateTime => {
-            let ts = parse_zoned_date_time_str(ts);
-            (ts - curr_ts).num_seconds()
+            let from_ts = parse_zoned_date_time_str(from, &time_zone)? - lead;
+            let to_ts = parse_zoned_date_time_str(to, &time_zone)? + lag;
+            Ok(((from_ts - curr_ts).num_seconds(), (to_ts - curr_ts).num_seconds()))
+        }
+        TimeRangeType::Cron => {
+            Ok((
+                next_cron_fire(from, &(curr_ts + lead), &time_zone)?.map_or(i64::MAX, |ts| (ts - lead - curr_ts).num_seconds()),
+                next_cron_fire(to, &(curr_ts - lag), &time_zone)?.map_or(i64::MAX, |ts| (ts + lag - curr_ts).num_seconds()),
+            ))
+        }
+    }
+}
+
+/// indices of every time range matching [curr_ts]
+pub fn matched_time_ranges(time_range_spec: &[TimeRangeSpec], curr_ts: DateTime<FixedOffset>) -> Result<Vec<usize>, Error> {
+    let mut matched_time_ranges = vec![];
+    for (index, time_range) in time_range_spec.iter().enumerate() {
+        if timestamp_match(time_range, curr_ts)? {
+            matched_time_ranges.push(index);
         }
     }
+    Ok(matched_time_ranges)
 }
 
-/// determines the "jump" factor and the next nearest target minReplicas/maxReplicas according to the distance from the nearest matching interval
-pub fn determine_next_target(default: i32, time_range_spec: &Vec<TimeRangeSpec>, is_max: bool) -> (i32, Option<i32>) {
-    let mut min_diff = i64::MAX;
-    let mut next_nearest_target = None;
+/// index of the applied time range among [matched_time_ranges]: highest priority wins, ties go to the range declared
+/// last
+pub fn active_time_range(time_range_spec: &[TimeRangeSpec], matched_time_ranges: &[usize]) -> Option<usize> {
+    matched_time_ranges.iter().copied().max_by_key(|index| time_range_spec[*index].priority.unwrap_or(0))
+}
+
+/// minReplicas/maxReplicas in effect at [ts]: the override of the applied time range, [default] if none matches
+fn target_at(default: i32, time_range_spec: &[TimeRangeSpec], is_max: bool, ts: DateTime<FixedOffset>) -> Result<Option<i32>, Error> {
+    let matched_time_ranges = matched_time_ranges(time_range_spec, ts)?;
+    Ok(match active_time_range(time_range_spec, &matched_time_ranges) {
+        Some(index) if is_max => time_range_spec[index].replica_spec.hpa.max_replicas,
+        Some(index) => time_range_spec[index].replica_spec.hpa.min_replicas,
+        None => Some(default),
+    })
+}
+
+/// (jump interval, next target, time range owning the next boundary, seconds until it)
+pub type NextTarget<'a> = (i32, Option<i32>, Option<&'a TimeRangeSpec>, i64);
+
+/// determines the "jump" factor and the next nearest target minReplicas/maxReplicas according to the distance from the nearest matching interval,
+/// along with the time range owning that boundary and the seconds left until it
+///
+/// boundaries are resolved by priority, a boundary that does not change the target in effect (Ex: a range starting
+/// while a range of higher priority is applied) is skipped
+pub fn determine_next_target(default: i32, time_range_spec: &[TimeRangeSpec], is_max: bool, curr_ts: DateTime<FixedOffset>) -> Result<NextTarget<'_>, Error> {
+    // boundaries fall on whole seconds, so that [curr_ts] + diff is the boundary instant
+    let curr_ts = curr_ts.trunc_subsecs(0);
+    // upcoming boundaries, nearest first, incase from and to are equally placed take from
+    let mut boundaries = vec![];
     for time_range in time_range_spec {
-        let diff_from_from = diff_from_now(&time_range.from, &time_range.kind);
-        let diff_from_to = diff_from_now(&time_range.to, &time_range.kind);
-
-        if diff_from_from < diff_from_to {
-            // incase from and to are equally placed take diff_from_from
-            if diff_from_from <= min_diff {
-                min_diff = diff_from_from;
-                if !is_max {
-                    next_nearest_target = time_range.replica_spec.hpa.min_replicas
-                } else {
-                    next_nearest_target = time_range.replica_spec.hpa.max_replicas
-                }
-            }
-        } else if diff_from_to < min_diff {
-            min_diff = diff_from_to;
-            next_nearest_target = Some(default)
+        let (diff_from_from, diff_from_to) = diff_from_now(time_range, curr_ts)?;
+        boundaries.push((diff_from_from, 0, time_range));
+        boundaries.push((diff_from_to, 1, time_range));
+    }
+    boundaries.retain(|(diff, _, _)| *diff >= 0 && *diff < i64::MAX);
+    boundaries.sort_by_key(|(diff, order, _)| (*diff, *order));
+    for (diff, _, time_range) in boundaries {
+        // ranges are matched exclusively, the target changes right after the boundary
+        let boundary_ts = curr_ts + Duration::seconds(diff);
+        let before = target_at(default, time_range_spec, is_max, boundary_ts - Duration::seconds(1))?;
+        let after = target_at(default, time_range_spec, is_max, boundary_ts + Duration::seconds(1))?;
+        if before != after {
+            return Ok(((diff / RECONCILIATION_PERIOD as i64).max(1i64) as i32, after, Some(time_range), diff));
         }
     }
-    ((min_diff / RECONCILIATION_PERIOD as i64).max(1i64) as i32, next_nearest_target)
+    Ok((i32::MAX, None, None, i64::MAX))
 }
 
+/// seconds until the next instant the desired hpa may change: a `from`/`to` boundary (lead/lag time applied) or a ramp
+/// step, ramp steps are taken every reconciliation period counting back from the boundary, capped at the period
+pub fn next_requeue_secs(time_range_spec: &[TimeRangeSpec], curr_ts: DateTime<FixedOffset>) -> Result<u64, Error> {
+    let period = RECONCILIATION_PERIOD as i64;
+    let mut diffs = vec![];
+    for time_range in time_range_spec {
+        let (diff_from_from, diff_from_to) = diff_from_now(time_range, curr_ts)?;
+        diffs.extend([diff_from_from, diff_from_to]);
+    }
+    Ok(diffs.into_iter()
+        .filter(|diff| *diff > 0 && *diff < i64::MAX)
+        .map(|diff| match diff % period {
+            0 => period,
+            until_step => until_step,
+        })
+        .min()
+        // land just past the boundary, ranges are matched exclusively
+        .map_or(RECONCILIATION_PERIOD, |diff| (diff + 1).min(period) as u64))
+}
+
+/// time of the next boundary (whole seconds), the minReplicas/maxReplicas it moves to and the name of the time range
+/// owning it, none if no boundary lies ahead
+pub fn next_transition(default: i32, time_range_spec: &[TimeRangeSpec], is_max: bool, curr_ts: DateTime<FixedOffset>) -> Result<Option<(DateTime<FixedOffset>, i32, String)>, Error> {
+    let (_, next_target, next_range, diff) = determine_next_target(default, time_range_spec, is_max, curr_ts)?;
+    let curr_ts = curr_ts.trunc_subsecs(0);
+    Ok(match (next_target, next_range) {
+        (Some(next_target), Some(next_range)) if diff > 0 && diff < i64::MAX => Some((curr_ts + Duration::seconds(diff), next_target, next_range.label())),
+        _ => None,
+    })
+}
+
+/// (step, steps) of the ramp towards the next boundary, none if not ramping
+pub fn ramp_progress(default: i32, time_range_spec: &[TimeRangeSpec], is_max: bool, curr_ts: DateTime<FixedOffset>) -> Result<Option<(i32, i32)>, Error> {
+    let (jump_interval, next_target, next_range, _) = determine_next_target(default, time_range_spec, is_max, curr_ts)?;
+    let ramp_curve = next_range.and_then(|time_range| time_range.ramp_curve.clone()).unwrap_or(RampCurve::Linear);
+    let ramp_intervals = ramp_intervals(next_range)?;
+    if next_target.is_none() || ramp_curve == RampCurve::Immediate || jump_interval > ramp_intervals {
+        return Ok(None);
+    }
+    Ok(Some((ramp_intervals - jump_interval + 1, ramp_intervals)))
+}
+
+/// `rampStep` of the status, Ex: `3 of 6`, each bound is named when minReplicas and maxReplicas ramp differently
+pub fn ramp_step_label(min_ramp_step: Option<(i32, i32)>, max_ramp_step: Option<(i32, i32)>) -> Option<String> {
+    match (min_ramp_step, max_ramp_step) {
+        (Some(min), Some(max)) if min != max => Some(format!("min {} of {}, max {} of {}", min.0, min.1, max.0, max.1)),
+        (Some((step, steps)), _) | (None, Some((step, steps))) => Some(format!("{} of {}", step, steps)),
+        (None, None) => None,
+    }
+}
 
-/// steps from [curr] to [next nearest target], falls back to default if no next target found, falls back to fallback if not within the "ramp-up/down" duration
+/// number of reconcile intervals the ramp towards a boundary of the time range takes, defaults to 30min ~(6 intervals),
+/// rounded up so that a ramp shorter than the period still takes one interval instead of none
+fn ramp_intervals(time_range: Option<&TimeRangeSpec>) -> Result<i32, Error> {
+    let period = RECONCILIATION_PERIOD as i64;
+    match time_range.and_then(|time_range| time_range.ramp_duration.as_deref()) {
+        Some(ramp_duration) => Ok(((parse_duration_str(ramp_duration)?.num_seconds() + period - 1) / period).clamp(1, i32::MAX as i64) as i32),
+        None => Ok(DEFAULT_RAMP_INTERVALS),
+    }
+}
+
+/// position of the ramp towards the [next nearest target] at [curr_ts], falls back to fallback if no next target found or
+/// if not within the "ramp-up/down" duration
 /// fallback is
 ///   * default: if ts_match=false
 ///   * actual_target: if ts_match=true
-/// ramp-up/down duration: 30min ~(6 intervals)
-pub fn step(curr: i32, default: i32, fallback: i32, time_range_spec: &Vec<TimeRangeSpec>, is_max: bool) -> Result<i32, Error> {
-    let (jump_interval, next_target) = determine_next_target(default, time_range_spec, is_max);
+///
+/// the ramp starts from fallback (the target in effect until the boundary) and only depends on the time left until
+/// the boundary, step k of n is applied during the k-th interval and the last one reaches the target
+///
+/// ramp-up/down duration and curve are taken from the time range owning the next boundary
+///   * Linear: equal steps, the default
+///   * Exponential: equal ratio steps, large absolute changes first when scaling down and last when scaling up
+///   * Immediate: no ramp, the target is applied once the boundary is crossed
+pub fn step(default: i32, fallback: i32, time_range_spec: &[TimeRangeSpec], is_max: bool, curr_ts: DateTime<FixedOffset>) -> Result<i32, Error> {
+    let (jump_interval, next_target, next_range, _) = determine_next_target(default, time_range_spec, is_max, curr_ts)?;
     if next_target.is_none() {
-        warn!("unable to determine next nearest target falling back to default!");
-        return Ok(default);
+        warn!("unable to determine next nearest target falling back to {}!", fallback);
+        return Ok(fallback);
     }
-    info!("intervals_left:{} next_target:{}", jump_interval, next_target.unwrap());
-    if jump_interval > 6 {
+    let next_target = next_target.unwrap();
+    let ramp_curve = next_range.and_then(|time_range| time_range.ramp_curve.clone()).unwrap_or(RampCurve::Linear);
+    let ramp_intervals = ramp_intervals(next_range)?;
+    info!("next_range:{} intervals_left:{} next_target:{} ramp_intervals:{} ramp_curve:{:?}", next_range.map_or("-".to_string(), |time_range| time_range.label()), jump_interval, next_target, ramp_intervals, ramp_curve);
+    if ramp_curve == RampCurve::Immediate || jump_interval > ramp_intervals {
         // falls back to default if ts_match=false, else it falls back to actual target
         warn!("greater than ramp up/down duration! falling back to {}", fallback);
         return Ok(fallback);
     }
-    if next_target.unwrap() == curr {
-        warn!("current already at target!");
-        return Ok(curr);
+
+    // fraction of the ramp done once this interval's step is applied
+    let progress = (ramp_intervals - jump_interval + 1) as f64 / ramp_intervals as f64;
+    let (start, target) = (fallback as f64, next_target as f64);
+    let position = match ramp_curve {
+        RampCurve::Exponential if fallback > 0 && next_target > 0 => start * (target / start).powf(progress),
+        _ => start + (target - start) * progress,
+    };
+    Ok((position.round() as i32).clamp(fallback.min(next_target), fallback.max(next_target)))
+}
+
+/// default custom metrics with the targets of [overrides] applied, metrics only present in [overrides] are added
+pub fn merge_metrics(default: &Option<Vec<CustomMetricSpec>>, overrides: &Option<Vec<CustomMetricSpec>>) -> Option<Vec<CustomMetricSpec>> {
+    let mut metrics = default.clone().unwrap_or_default();
+    for metric in overrides.iter().flatten() {
+        match metrics.iter_mut().find(|default| default.same_metric(metric)) {
+            Some(default) => *default = metric.clone(),
+            None => metrics.push(metric.clone()),
+        }
+    }
+    if metrics.is_empty() { None } else { Some(metrics) }
+}
+
+/// default behavior with the `scaleUp`/`scaleDown` rules set in [overrides] replaced
+pub fn merge_behavior(default: &Option<HpaBehavior>, overrides: &Option<HpaBehavior>) -> Option<HpaBehavior> {
+    match (default, overrides) {
+        (Some(default), Some(overrides)) => Some(HpaBehavior {
+            scale_up: overrides.scale_up.clone().or(default.scale_up.clone()),
+            scale_down: overrides.scale_down.clone().or(default.scale_down.clone()),
+        }),
+        (default, None) => default.clone(),
+        (None, overrides) => overrides.clone(),
     }
+}
 
-    let step = (next_target.unwrap() - curr) / jump_interval;
-    if curr > next_target.unwrap() {
-        Ok((curr + step).max(next_target.unwrap()))
-    } else {
-        Ok((curr + step).min(next_target.unwrap()))
+/// limits the move from [curr] to [desired] according to the scale-up/down policy of that direction
+pub fn limit_step(curr: i32, desired: i32, policy: &Option<StepPolicy>, last_step_time: &Option<String>, curr_ts: DateTime<FixedOffset>) -> Result<i32, Error> {
+    let policy = match policy {
+        Some(policy) if desired != curr => policy,
+        _ => return Ok(desired),
+    };
+    if let (Some(delay), Some(last_step_time)) = (&policy.stabilization_delay, last_step_time) {
+        let delay_duration = parse_duration_str(delay)?;
+        match DateTime::parse_from_rfc3339(last_step_time) {
+            Ok(last_step_ts) if curr_ts < last_step_ts + delay_duration => {
+                info!("within stabilization delay:{} since:{}! holding at {}", delay, last_step_time, curr);
+                return Ok(curr);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("ignoring unparsable last step time:{}! err: {:?}", last_step_time, e),
+        }
+    }
+    // every step moves at least one replica towards [desired], i64 so that large percentages do not overflow
+    let (curr, desired) = (curr as i64, desired as i64);
+    let mut max_step = (desired - curr).abs();
+    if let Some(max_replicas_per_step) = policy.max_replicas_per_step {
+        max_step = max_step.min((max_replicas_per_step as i64).max(1));
+    }
+    if let Some(max_percent_per_step) = policy.max_percent_per_step {
+        max_step = max_step.min((curr * max_percent_per_step as i64 / 100).max(1));
     }
+    Ok((curr + (desired - curr).signum() * max_step) as i32)
+}
+
+/// marks the ServiceScaler degraded (and not ready) with the reconcile error, the rest of the status is kept
+pub async fn patch_degraded(client: Client, namespace: &str, name: &str, reason: &str, message: &str) -> Result<(), Error> {
+    let api: Api<ServiceScaler> = Api::namespaced(client.clone(), namespace);
+    let mut status = match api.get(name).await {
+        Ok(service_scaler) => service_scaler.status.unwrap_or_default(),
+        Err(_) => return Ok(()),
+    };
+    status.set_condition(CONDITION_READY, false, reason, message);
+    status.set_condition(CONDITION_DEGRADED, true, reason, message);
+    patch_status(client, namespace, name, "degraded", status).await
 }
 
-pub async fn patch_status(client: Client, namespace: &str, name: &str, time_range_match: bool, _action: &str, hpa_spec: &HpaOverrideSpec) -> Result<(), Error> {
+/// patches the status, `lastObservedGeneration` and `lastUpdatedTime` are filled in here, skipped if nothing else
+/// changed
+pub async fn patch_status(client: Client, namespace: &str, name: &str, _action: &str, status: ServiceScalerStatus) -> Result<(), Error> {
     let api: Api<ServiceScaler> = Api::namespaced(client, namespace);
     let curr_ts = Local::now().fixed_offset();
     match api.get(name).await {
         Ok(service_scaler) => {
+            let status = ServiceScalerStatus {
+                last_observed_generation: service_scaler.meta().generation,
+                ..status
+            };
+            if let Some(curr_status) = &service_scaler.status {
+                if *curr_status == (ServiceScalerStatus { last_updated_time: curr_status.last_updated_time.clone(), ..status.clone() }) {
+                    debug!("[{}] status unchanged! skipping status patch!", key(namespace, name));
+                    return Ok(());
+                }
+            }
             let mut patch = service_scaler.clone();
             patch.status = Some(ServiceScalerStatus {
-                time_range_match,
-                last_observed_generation: service_scaler.meta().generation,
-                last_known_config: hpa_spec.clone(),
                 last_updated_time: curr_ts.format("%Y-%m-%dT%H:%MZ%z").to_string(),
+                ..status
             });
-            api.patch_status(name, &PatchParams::default(), &Patch::Merge(&patch)).await.expect("patch_status errored!");
+            // the patch carries the resourceVersion, a concurrent write fails with a conflict and is retried by the caller
+            api.patch_status(name, &patch_params(), &Patch::Merge(&patch)).await?;
             info!("[{}] patched status!", key(namespace, name));
             Ok(())
         }
@@ -155,4 +595,221 @@ pub async fn patch_status(client: Client, namespace: &str, name: &str, time_rang
             Ok(())
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn ts(rfc3339: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap()
+    }
+
+    fn time_range(value: serde_json::Value) -> TimeRangeSpec {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// 12:00-18:00 IST on 2024-03-06 (a Wednesday) overriding minReplicas to 10, ramps over 30m (6 intervals)
+    fn peak(ramp_curve: &str) -> Vec<TimeRangeSpec> {
+        vec![time_range(json!({
+            "kind": "ZonedDateTime",
+            "from": "2024-03-06T12:00:00+05:30",
+            "to": "2024-03-06T18:00:00+05:30",
+            "rampDuration": "30m",
+            "rampCurve": ramp_curve,
+            "replicaSpec": {"hpa": {"minReplicas": 10}}
+        }))]
+    }
+
+    #[test]
+    fn resolve_local_dst_gap_resolves_after_the_gap() {
+        // 01:30 does not exist in London on 2024-03-31, clocks jump from 01:00 GMT to 02:00 BST
+        let local = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap().and_hms_opt(1, 30, 0).unwrap();
+        assert_eq!(resolve_local(&Tz::Europe__London, local), ts("2024-03-31T02:00:00+01:00"));
+    }
+
+    #[test]
+    fn resolve_local_dst_overlap_resolves_to_first_occurrence() {
+        // 01:30 happens twice in London on 2024-10-27, first in BST then in GMT
+        let local = NaiveDate::from_ymd_opt(2024, 10, 27).unwrap().and_hms_opt(1, 30, 0).unwrap();
+        assert_eq!(resolve_local(&Tz::Europe__London, local), ts("2024-10-27T01:30:00+01:00"));
+    }
+
+    #[test]
+    fn zoned_time_crossing_midnight_belongs_to_the_day_it_starts_on() {
+        let friday_night = time_range(json!({
+            "kind": "ZonedTime",
+            "from": "22:00+05:30",
+            "to": "02:00+05:30",
+            "daysOfWeek": ["Fri"],
+            "replicaSpec": {"hpa": {}}
+        }));
+        // 2024-03-08 is a Friday
+        assert!(timestamp_match(&friday_night, ts("2024-03-08T23:00:00+05:30")).unwrap());
+        assert!(timestamp_match(&friday_night, ts("2024-03-09T01:00:00+05:30")).unwrap());
+        assert!(!timestamp_match(&friday_night, ts("2024-03-08T01:00:00+05:30")).unwrap());
+        assert!(!timestamp_match(&friday_night, ts("2024-03-09T23:00:00+05:30")).unwrap());
+    }
+
+    #[test]
+    fn cron_matches_between_from_and_to_fires() {
+        let office_hours = time_range(json!({
+            "kind": "Cron",
+            "from": "0 9 * * MON-FRI",
+            "to": "0 17 * * MON-FRI",
+            "timeZone": "Asia/Kolkata",
+            "replicaSpec": {"hpa": {}}
+        }));
+        assert!(timestamp_match(&office_hours, ts("2024-03-06T10:00:00+05:30")).unwrap());
+        assert!(!timestamp_match(&office_hours, ts("2024-03-06T18:00:00+05:30")).unwrap());
+        // saturday, the last `to` fired after the last `from`
+        assert!(!timestamp_match(&office_hours, ts("2024-03-09T10:00:00+05:30")).unwrap());
+        assert_eq!(diff_from_now(&office_hours, ts("2024-03-06T08:00:00+05:30")).unwrap(), (3600, 9 * 3600));
+    }
+
+    #[test]
+    fn cron_rejects_numeric_days_of_week_in_crontab_expressions() {
+        assert!(check_time_str("from", &TimeRangeType::Cron, "0 9 * * 1-5", &None).is_err());
+        assert!(check_time_str("from", &TimeRangeType::Cron, "0 9 * * MON-FRI", &None).is_ok());
+        assert!(check_time_str("from", &TimeRangeType::Cron, "0 0 9 * * MON-FRI", &None).is_ok());
+        assert!(check_time_str("from", &TimeRangeType::Cron, "every morning", &None).is_err());
+    }
+
+    #[test]
+    fn determine_next_target_skips_boundaries_shadowed_by_a_higher_priority_range() {
+        let range = |from: &str, to: &str, priority: i32, min_replicas: i32| time_range(json!({
+            "kind": "ZonedDateTime",
+            "from": format!("2024-03-06T{}:00+05:30", from),
+            "to": format!("2024-03-06T{}:00+05:30", to),
+            "priority": priority,
+            "replicaSpec": {"hpa": {"minReplicas": min_replicas}}
+        }));
+        let time_range_spec = vec![range("10:00", "20:00", 1, 8), range("12:00", "14:00", 0, 20), range("15:00", "16:00", 5, 12)];
+        // 12:00 and 14:00 fall within the higher priority 10:00-20:00 range, the next change is 15:00
+        let (jump_interval, next_target, next_range, diff) = determine_next_target(2, &time_range_spec, false, ts("2024-03-06T11:00:00+05:30")).unwrap();
+        assert_eq!((jump_interval, next_target, diff), (48, Some(12), 4 * 3600));
+        assert_eq!(next_range, Some(&time_range_spec[2]));
+        // back to the 10:00-20:00 range at 16:00, the default at 20:00
+        assert_eq!(determine_next_target(2, &time_range_spec, false, ts("2024-03-06T15:30:00+05:30")).unwrap().1, Some(8));
+        assert_eq!(determine_next_target(2, &time_range_spec, false, ts("2024-03-06T17:00:00+05:30")).unwrap().1, Some(2));
+        // nothing ahead once every range is over
+        assert_eq!(determine_next_target(2, &time_range_spec, false, ts("2024-03-06T21:00:00+05:30")).unwrap().1, None);
+    }
+
+    #[test]
+    fn step_linear_ramps_by_elapsed_intervals() {
+        let time_range_spec = peak("Linear");
+        // up towards `from`, starting from the default
+        assert_eq!(step(2, 2, &time_range_spec, false, ts("2024-03-06T11:00:00+05:30")).unwrap(), 2);
+        assert_eq!(step(2, 2, &time_range_spec, false, ts("2024-03-06T11:40:00+05:30")).unwrap(), 6);
+        assert_eq!(step(2, 2, &time_range_spec, false, ts("2024-03-06T11:55:00+05:30")).unwrap(), 10);
+        // down towards `to`, starting from the override
+        assert_eq!(step(2, 10, &time_range_spec, false, ts("2024-03-06T17:40:00+05:30")).unwrap(), 6);
+    }
+
+    #[test]
+    fn step_exponential_ramps_by_equal_ratios() {
+        let time_range_spec = peak("Exponential");
+        assert_eq!(step(2, 2, &time_range_spec, false, ts("2024-03-06T11:40:00+05:30")).unwrap(), 4);
+        assert_eq!(step(2, 10, &time_range_spec, false, ts("2024-03-06T17:40:00+05:30")).unwrap(), 4);
+        assert_eq!(step(2, 2, &time_range_spec, false, ts("2024-03-06T11:55:00+05:30")).unwrap(), 10);
+    }
+
+    #[test]
+    fn step_immediate_holds_until_the_boundary() {
+        assert_eq!(step(2, 2, &peak("Immediate"), false, ts("2024-03-06T11:55:00+05:30")).unwrap(), 2);
+        assert_eq!(step(2, 10, &peak("Immediate"), false, ts("2024-03-06T12:00:01+05:30")).unwrap(), 10);
+    }
+
+    #[test]
+    fn merge_behavior_replaces_the_rules_set_in_the_override() {
+        let default: Option<HpaBehavior> = serde_json::from_value(json!({
+            "scaleUp": {"stabilizationWindowSeconds": 0},
+            "scaleDown": {"stabilizationWindowSeconds": 300}
+        })).unwrap();
+        let overrides: Option<HpaBehavior> = serde_json::from_value(json!({"scaleDown": {"stabilizationWindowSeconds": 1800}})).unwrap();
+        let merged = merge_behavior(&default, &overrides).unwrap();
+        assert_eq!(merged.scale_up.and_then(|rules| rules.stabilization_window_seconds), Some(0));
+        assert_eq!(merged.scale_down.and_then(|rules| rules.stabilization_window_seconds), Some(1800));
+        assert_eq!(merge_behavior(&default, &None), default);
+        assert_eq!(merge_behavior(&None, &overrides), overrides);
+    }
+
+    #[test]
+    fn limit_step_caps_the_move_per_step() {
+        let curr_ts = ts("2024-03-06T12:00:00+05:30");
+        let per_step = |replicas: Option<i32>, percent: Option<i32>| Some(StepPolicy { max_replicas_per_step: replicas, max_percent_per_step: percent, stabilization_delay: None });
+        assert_eq!(limit_step(4, 10, &None, &None, curr_ts).unwrap(), 10);
+        assert_eq!(limit_step(4, 10, &per_step(Some(2), None), &None, curr_ts).unwrap(), 6);
+        assert_eq!(limit_step(10, 2, &per_step(Some(3), None), &None, curr_ts).unwrap(), 7);
+        assert_eq!(limit_step(4, 10, &per_step(None, Some(50)), &None, curr_ts).unwrap(), 6);
+        // at least one replica per step
+        assert_eq!(limit_step(4, 10, &per_step(None, Some(10)), &None, curr_ts).unwrap(), 5);
+        // no i32 overflow on large values
+        assert_eq!(limit_step(100_000_000, i32::MAX, &per_step(None, Some(100)), &None, curr_ts).unwrap(), 200_000_000);
+    }
+
+    #[test]
+    fn limit_step_holds_within_the_stabilization_delay() {
+        let policy = Some(StepPolicy { max_replicas_per_step: None, max_percent_per_step: None, stabilization_delay: Some("10m".to_string()) });
+        let curr_ts = ts("2024-03-06T12:00:00+05:30");
+        assert_eq!(limit_step(4, 10, &policy, &Some("2024-03-06T11:55:00+05:30".to_string()), curr_ts).unwrap(), 4);
+        assert_eq!(limit_step(4, 10, &policy, &Some("2024-03-06T11:45:00+05:30".to_string()), curr_ts).unwrap(), 10);
+        // unparsable last step times are ignored
+        assert_eq!(limit_step(4, 10, &policy, &Some("yesterday".to_string()), curr_ts).unwrap(), 10);
+    }
+
+    #[test]
+    fn merge_metrics_replaces_targets_of_the_same_metric() {
+        let default: Option<Vec<CustomMetricSpec>> = serde_json::from_value(json!([
+            {"type": "Pods", "name": "queue_depth", "target": {"type": "AverageValue", "averageValue": "10"}},
+            {"type": "External", "name": "rps", "target": {"type": "Value", "value": "100"}}
+        ])).unwrap();
+        let overrides: Option<Vec<CustomMetricSpec>> = serde_json::from_value(json!([
+            {"type": "Pods", "name": "queue_depth", "target": {"type": "AverageValue", "averageValue": "20"}},
+            {"type": "Pods", "name": "latency", "target": {"type": "AverageValue", "averageValue": "500m"}}
+        ])).unwrap();
+        let merged = merge_metrics(&default, &overrides).unwrap();
+        assert_eq!(merged.iter().map(|metric| metric.name.as_str()).collect::<Vec<_>>(), vec!["queue_depth", "rps", "latency"]);
+        assert_eq!(merged[0].target.average_value.as_deref(), Some("20"));
+        assert_eq!(merge_metrics(&None, &None), None);
+    }
+
+    #[test]
+    fn next_requeue_secs_lands_on_the_next_ramp_step_or_boundary() {
+        let time_range_spec = peak("Linear");
+        // ramp steps are taken every period counting back from the boundary, just past it
+        assert_eq!(next_requeue_secs(&time_range_spec, ts("2024-03-06T11:40:10+05:30")).unwrap(), 291);
+        assert_eq!(next_requeue_secs(&time_range_spec, ts("2024-03-06T11:57:30+05:30")).unwrap(), 151);
+        assert_eq!(next_requeue_secs(&time_range_spec, ts("2024-03-06T17:59:59+05:30")).unwrap(), 2);
+        // capped at the period
+        assert_eq!(next_requeue_secs(&time_range_spec, ts("2024-03-06T09:00:00+05:30")).unwrap(), RECONCILIATION_PERIOD);
+        assert_eq!(next_requeue_secs(&time_range_spec, ts("2024-03-06T19:00:00+05:30")).unwrap(), RECONCILIATION_PERIOD);
+    }
+
+    #[test]
+    fn ramp_progress_counts_intervals_towards_the_boundary() {
+        let time_range_spec = peak("Linear");
+        assert_eq!(ramp_progress(2, &time_range_spec, false, ts("2024-03-06T11:00:00+05:30")).unwrap(), None);
+        assert_eq!(ramp_progress(2, &time_range_spec, false, ts("2024-03-06T11:40:00+05:30")).unwrap(), Some((3, 6)));
+        assert_eq!(ramp_progress(2, &time_range_spec, false, ts("2024-03-06T11:55:00.500+05:30")).unwrap(), Some((6, 6)));
+        assert_eq!(ramp_progress(2, &peak("Immediate"), false, ts("2024-03-06T11:40:00+05:30")).unwrap(), None);
+    }
+
+    #[test]
+    fn ramp_step_label_reports_every_ramping_bound() {
+        assert_eq!(ramp_step_label(None, None), None);
+        assert_eq!(ramp_step_label(Some((3, 6)), None), Some("3 of 6".to_string()));
+        assert_eq!(ramp_step_label(None, Some((1, 4))), Some("1 of 4".to_string()));
+        assert_eq!(ramp_step_label(Some((3, 6)), Some((3, 6))), Some("3 of 6".to_string()));
+        assert_eq!(ramp_step_label(Some((3, 6)), Some((1, 4))), Some("min 3 of 6, max 1 of 4".to_string()));
+    }
+
+    #[test]
+    fn next_transition_is_the_boundary_instant() {
+        let (transition_ts, target, _) = next_transition(2, &peak("Linear"), false, ts("2024-03-06T11:40:12.750+05:30")).unwrap().unwrap();
+        assert_eq!(transition_ts, ts("2024-03-06T12:00:00+05:30"));
+        assert_eq!(target, 10);
+    }
+}
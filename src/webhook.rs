@@ -0,0 +1,375 @@
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::path::Path;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, FixedOffset, Local};
+use axum_server::tls_rustls::RustlsConfig;
+use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview};
+use kube::core::DynamicObject;
+use kube::{Resource, ResourceExt};
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use crate::crd::{CustomMetricSpec, CustomMetricType, HpaSpec, MetricTargetType, ScalingCalendar, ScalingCalendarSpec, ServiceScaler, ServiceScalerSpec, TimeRangeSpec, TimeRangeType};
+use crate::hpa::parse_quantity;
+use crate::util::{check_duration_str, check_time_str, check_time_zone, key, time_range_windows, Window, WEBHOOK_CERT, WEBHOOK_KEY, WEBHOOK_PORT};
+
+/// utilization targets are percentages of the requests, `0` disables the metric in overrides
+fn check_utilization(field: &str, utilization: Option<i32>, allow_zero: bool) -> Result<(), String> {
+    match utilization {
+        Some(utilization) if utilization == 0 && allow_zero => Ok(()),
+        Some(utilization) if !(1..=100).contains(&utilization) => Err(format!("{}: {} is out of range, expected 1-100{}", field, utilization, if allow_zero { " (0 disables)" } else { "" })),
+        _ => Ok(()),
+    }
+}
+
+/// Object metrics name their `describedObject`, ContainerResource metrics their `container`, the target of the metric
+/// type is set, quantities parse and are positive
+fn check_metrics(field: &str, metrics: &Option<Vec<CustomMetricSpec>>, errors: &mut Vec<String>) {
+    for (index, metric) in metrics.iter().flatten().enumerate() {
+        let field = format!("{}[{}]", field, index);
+        match metric.type_ {
+            CustomMetricType::Object if metric.described_object.is_none() => errors.push(format!("{}.describedObject: required for type Object", field)),
+            CustomMetricType::ContainerResource if metric.container.as_deref().unwrap_or_default().is_empty() => errors.push(format!("{}.container: required for type ContainerResource", field)),
+            _ => {}
+        }
+        let field = format!("{}.target", field);
+        let target = &metric.target;
+        if let Err(e) = check_utilization(&format!("{}.averageUtilization", field), target.average_utilization, false) {
+            errors.push(e);
+        }
+        let (required, set) = match target.type_ {
+            MetricTargetType::Utilization => ("averageUtilization", target.average_utilization.is_some()),
+            MetricTargetType::Value => ("value", target.value.is_some()),
+            MetricTargetType::AverageValue => ("averageValue", target.average_value.is_some()),
+        };
+        if !set {
+            errors.push(format!("{}.{}: required for type {:?}", field, required, target.type_));
+        }
+        for (name, quantity) in [("value", &target.value), ("averageValue", &target.average_value)] {
+            match quantity.as_deref().map(|quantity| (quantity, parse_quantity(quantity))) {
+                Some((quantity, None)) => errors.push(format!("{}.{}: {:?} is not a quantity, Ex: 100 or 500m", field, name, quantity)),
+                Some((quantity, Some(value))) if value <= 0f64 => errors.push(format!("{}.{}: {:?} is not positive", field, name, quantity)),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// checks a time range, unset replica bounds are taken from [defaults] (the ServiceScaler `hpa`, none for calendar
+/// entries), the windows of a valid range are returned
+fn check_time_range_spec(field: &str, time_range: &TimeRangeSpec, defaults: Option<&HpaSpec>, curr_ts: DateTime<FixedOffset>, errors: &mut Vec<String>) -> Option<Vec<Window>> {
+    let errors_before = errors.len();
+    if time_range.days_of_week.is_some() && !matches!(time_range.kind, TimeRangeType::ZonedTime) {
+        errors.push(format!("{}.daysOfWeek: only supported on ZonedTime ranges, restrict {:?} ranges with a Cron range instead", field, time_range.kind));
+    }
+    if let Some(time_zone) = &time_range.time_zone {
+        errors.extend(check_time_zone(&format!("{}.timeZone", field), time_zone).err());
+    }
+    if errors.len() == errors_before {
+        errors.extend(check_time_str(&format!("{}.from", field), &time_range.kind, &time_range.from, &time_range.time_zone).err());
+        errors.extend(check_time_str(&format!("{}.to", field), &time_range.kind, &time_range.to, &time_range.time_zone).err());
+    }
+    for (name, duration) in [("leadTime", &time_range.lead_time), ("lagTime", &time_range.lag_time), ("rampDuration", &time_range.ramp_duration)] {
+        if let Some(duration) = duration {
+            errors.extend(check_duration_str(&format!("{}.{}", field, name), duration).err());
+        }
+    }
+    let overrides = &time_range.replica_spec.hpa;
+    // unset bounds fall back to the defaults
+    let min_replicas = overrides.min_replicas.or(defaults.map(|hpa| hpa.min_replicas));
+    let max_replicas = overrides.max_replicas.or(defaults.map(|hpa| hpa.max_replicas));
+    if let (Some(min_replicas), Some(max_replicas)) = (min_replicas, max_replicas) {
+        if (overrides.min_replicas.is_some() || overrides.max_replicas.is_some()) && min_replicas > max_replicas {
+            errors.push(format!("{}.replicaSpec.hpa: minReplicas {} is greater than maxReplicas {}", field, min_replicas, max_replicas));
+        }
+    }
+    errors.extend(check_utilization(&format!("{}.replicaSpec.hpa.targetCPUUtilization", field), overrides.target_cpu_utilization, true).err());
+    errors.extend(check_utilization(&format!("{}.replicaSpec.hpa.targetMemoryUtilization", field), overrides.target_memory_utilization, true).err());
+    check_metrics(&format!("{}.replicaSpec.hpa.metrics", field), &overrides.metrics, errors);
+    if errors.len() != errors_before {
+        return None;
+    }
+    match time_range_windows(time_range, curr_ts) {
+        Ok(windows) if windows.iter().any(|(from_ts, to_ts)| from_ts >= to_ts) => {
+            errors.push(format!("{}: from {:?} is not before to {:?}", field, time_range.from, time_range.to));
+            None
+        }
+        Ok(windows) => Some(windows),
+        Err(e) => {
+            errors.push(format!("{}: {}", field, e));
+            None
+        }
+    }
+}
+
+/// every reason the spec is rejected, empty if valid
+pub fn validate(spec: &ServiceScalerSpec, curr_ts: DateTime<FixedOffset>) -> Vec<String> {
+    let mut errors = vec![];
+    let hpa = &spec.hpa;
+    if hpa.min_replicas < 1 {
+        errors.push(format!("hpa.minReplicas: {} is out of range, expected at least 1", hpa.min_replicas));
+    }
+    if hpa.min_replicas > hpa.max_replicas {
+        errors.push(format!("hpa: minReplicas {} is greater than maxReplicas {}", hpa.min_replicas, hpa.max_replicas));
+    }
+    errors.extend(check_utilization("hpa.targetCPUUtilization", hpa.target_cpu_utilization, false).err());
+    errors.extend(check_utilization("hpa.targetMemoryUtilization", hpa.target_memory_utilization, false).err());
+    check_metrics("hpa.metrics", &hpa.metrics, &mut errors);
+    if let Some(time_zone) = &spec.time_zone {
+        errors.extend(check_time_zone("timeZone", time_zone).err());
+    }
+    if let Some(ramp_duration) = &spec.ramp_duration {
+        errors.extend(check_duration_str("rampDuration", ramp_duration).err());
+    }
+    for (field, policy) in [("scaleUp", &spec.scale_up), ("scaleDown", &spec.scale_down)].into_iter().filter_map(|(field, policy)| policy.as_ref().map(|policy| (field, policy))) {
+        for (name, limit) in [("maxReplicasPerStep", policy.max_replicas_per_step), ("maxPercentPerStep", policy.max_percent_per_step)] {
+            if let Some(limit) = limit.filter(|limit| *limit < 1) {
+                errors.push(format!("{}.{}: {} is out of range, expected at least 1", field, name, limit));
+            }
+        }
+        if let Some(stabilization_delay) = &policy.stabilization_delay {
+            errors.extend(check_duration_str(&format!("{}.stabilizationDelay", field), stabilization_delay).err());
+        }
+    }
+
+    // time ranges, with the ServiceScaler level time zone and ramp settings filled in
+    let time_range_spec = spec.effective_time_range_spec(&[]);
+    let valid_ranges: Vec<(usize, &TimeRangeSpec, Vec<Window>)> = time_range_spec.iter().enumerate()
+        .filter_map(|(index, time_range)| {
+            check_time_range_spec(&format!("timeRangeSpec[{}]", index), time_range, Some(hpa), curr_ts, &mut errors)
+                .map(|windows| (index, time_range, windows))
+        })
+        .collect();
+    check_overlaps(&valid_ranges, &mut errors);
+    errors
+}
+
+/// rejects valid time ranges of the same priority overlapping within the validated windows, the applied range
+/// would only depend on the declaration order
+fn check_overlaps(valid_ranges: &[(usize, &TimeRangeSpec, Vec<Window>)], errors: &mut Vec<String>) {
+    for (i, (index, time_range, windows)) in valid_ranges.iter().enumerate() {
+        for (other_index, other_time_range, other_windows) in valid_ranges.iter().skip(i + 1) {
+            if time_range.priority.unwrap_or(0) != other_time_range.priority.unwrap_or(0) {
+                continue;
+            }
+            let overlap = windows.iter().find_map(|(from_ts, to_ts)| {
+                other_windows.iter()
+                    .find(|(other_from_ts, other_to_ts)| from_ts < other_to_ts && other_from_ts < to_ts)
+                    .map(|(other_from_ts, _)| (*from_ts).max(*other_from_ts))
+            });
+            if let Some(overlap_ts) = overlap {
+                errors.push(format!(
+                    "timeRangeSpec[{}]: overlaps timeRangeSpec[{}] ({}) from {} with the same priority {}, set distinct priorities",
+                    other_index, index, time_range.label(), overlap_ts, time_range.priority.unwrap_or(0)
+                ));
+            }
+        }
+    }
+}
+
+/// every reason the calendar is rejected, empty if valid, entries are checked without the time zone of the
+/// ServiceScalers referencing the calendar
+pub fn validate_calendar(spec: &ScalingCalendarSpec, curr_ts: DateTime<FixedOffset>) -> Vec<String> {
+    let mut errors = vec![];
+    for (index, entry) in spec.entries.iter().enumerate() {
+        check_time_range_spec(&format!("entries[{}]", index), &TimeRangeSpec::from(entry.clone()), None, curr_ts, &mut errors);
+    }
+    errors
+}
+
+/// admission review of creates and updates, objects being deleted and updates leaving the spec untouched (Ex:
+/// finalizers, labels) are allowed without validation, [check] returns the errors of an object
+fn admit<K>(review: AdmissionReview<K>, same_spec: impl Fn(&K, &K) -> bool, check: impl Fn(&K) -> Vec<String>) -> AdmissionReview<DynamicObject>
+where
+    K: Resource + ResourceExt + Clone + DeserializeOwned + Debug,
+{
+    let request: AdmissionRequest<K> = match review.try_into() {
+        Ok(request) => request,
+        Err(e) => {
+            error!("invalid admission review! err: {:?}", e);
+            return AdmissionResponse::invalid(e.to_string()).into_review();
+        }
+    };
+    let mut response = AdmissionResponse::from(&request);
+    let object = match &request.object {
+        Some(object) => object,
+        None => return response.into_review(),
+    };
+    if object.meta().deletion_timestamp.is_some() || request.old_object.as_ref().is_some_and(|old_object| same_spec(old_object, object)) {
+        return response.into_review();
+    }
+    let errors = check(object);
+    if !errors.is_empty() {
+        warn!("[{}] rejected! errors: {:?}", key(&request.namespace.clone().unwrap_or_default(), &object.name_any()), errors);
+        response = response.deny(errors.join("; "));
+    }
+    response.into_review()
+}
+
+/// `/validate` handler, admission review of ServiceScaler creates and updates
+async fn review(Json(review): Json<AdmissionReview<ServiceScaler>>) -> Json<AdmissionReview<DynamicObject>> {
+    Json(admit(review, |old, new| old.spec == new.spec, |service_scaler| validate(&service_scaler.spec, Local::now().fixed_offset())))
+}
+
+/// `/validate-calendar` handler, admission review of ScalingCalendar creates and updates
+async fn review_calendar(Json(review): Json<AdmissionReview<ScalingCalendar>>) -> Json<AdmissionReview<DynamicObject>> {
+    Json(admit(review, |old, new| old.spec == new.spec, |calendar| validate_calendar(&calendar.spec, Local::now().fixed_offset())))
+}
+
+/// `/readyz` handler of the webhook, ready once the certificate is loaded, independently of the operator readiness
+/// which follows the lease
+async fn webhook_readyz() -> &'static str {
+    "ok"
+}
+
+/// serves the validating webhook over https on [WEBHOOK_PORT] from every replica (leader or not, the webhook fails
+/// closed), skipped if the certificate is not mounted
+pub async fn serve() {
+    if !Path::new(WEBHOOK_CERT.as_str()).exists() || !Path::new(WEBHOOK_KEY.as_str()).exists() {
+        warn!("webhook certificate {} not found! validating webhook disabled!", WEBHOOK_CERT.as_str());
+        return;
+    }
+    let tls_config = RustlsConfig::from_pem_file(WEBHOOK_CERT.as_str(), WEBHOOK_KEY.as_str())
+        .await
+        .expect("Failed to load the webhook certificate");
+    let router = Router::new()
+        .route("/validate", post(review))
+        .route("/validate-calendar", post(review_calendar))
+        .route("/readyz", get(webhook_readyz));
+    let addr = SocketAddr::from(([0, 0, 0, 0], *WEBHOOK_PORT));
+    info!("serving validating webhook on {}", addr);
+    axum_server::bind_rustls(addr, tls_config)
+        .serve(router.into_make_service())
+        .await
+        .expect("webhook server errored!");
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use super::*;
+
+    fn curr_ts() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2024-03-06T10:00:00+05:30").unwrap()
+    }
+
+    /// ServiceScaler spec with [time_range_spec] and 2-10 replicas by default
+    fn spec(time_range_spec: Value) -> ServiceScalerSpec {
+        serde_json::from_value(json!({
+            "hpa": {"minReplicas": 2, "maxReplicas": 10, "targetCPUUtilization": 70},
+            "timeRangeSpec": time_range_spec
+        })).unwrap()
+    }
+
+    fn zoned_time(from: &str, to: &str, priority: i32) -> Value {
+        json!({"kind": "ZonedTime", "from": from, "to": to, "priority": priority, "replicaSpec": {"hpa": {"minReplicas": 4}}})
+    }
+
+    #[test]
+    fn valid_spec_is_admitted() {
+        let spec = spec(json!([
+            zoned_time("09:00+05:30", "18:00+05:30", 0),
+            {"kind": "Cron", "from": "0 19 * * SAT", "to": "0 23 * * SAT", "replicaSpec": {"hpa": {"minReplicas": 3}}}
+        ]));
+        assert_eq!(validate(&spec, curr_ts()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn inverted_bounds_and_malformed_times_are_rejected() {
+        let mut spec = spec(json!([
+            {"kind": "ZonedTime", "from": "25:00+05:30", "to": "18:00+05:30", "replicaSpec": {"hpa": {}}},
+            {"kind": "ZonedTime", "from": "09:00+05:30", "to": "18:00+05:30", "replicaSpec": {"hpa": {"minReplicas": 12}}},
+            {"kind": "Cron", "from": "0 9 * * 1-5", "to": "0 18 * * MON-FRI", "replicaSpec": {"hpa": {}}}
+        ]));
+        spec.hpa.min_replicas = 11;
+        let errors = validate(&spec, curr_ts());
+        assert!(errors.iter().any(|e| e.starts_with("hpa: minReplicas 11 is greater than maxReplicas 10")), "{:?}", errors);
+        assert!(errors.iter().any(|e| e.starts_with("timeRangeSpec[0].from")), "{:?}", errors);
+        assert!(errors.iter().any(|e| e.starts_with("timeRangeSpec[1].replicaSpec.hpa: minReplicas 12")), "{:?}", errors);
+        assert!(errors.iter().any(|e| e.starts_with("timeRangeSpec[2].from: numeric day of week")), "{:?}", errors);
+    }
+
+    #[test]
+    fn days_of_week_are_rejected_outside_zoned_time_ranges() {
+        let spec = spec(json!([
+            {"kind": "ZonedTime", "from": "09:00+05:30", "to": "12:00+05:30", "daysOfWeek": ["Sat"], "replicaSpec": {"hpa": {}}},
+            {"kind": "Cron", "from": "0 13 * * *", "to": "0 15 * * *", "daysOfWeek": ["Sat"], "replicaSpec": {"hpa": {}}},
+            {"kind": "ZonedDateTime", "from": "2024-03-09T16:00:00+05:30", "to": "2024-03-09T18:00:00+05:30", "daysOfWeek": ["Sat"], "replicaSpec": {"hpa": {}}}
+        ]));
+        let errors = validate(&spec, curr_ts());
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].starts_with("timeRangeSpec[1].daysOfWeek: only supported on ZonedTime ranges"));
+        assert!(errors[1].starts_with("timeRangeSpec[2].daysOfWeek: only supported on ZonedTime ranges"));
+    }
+
+    #[test]
+    fn step_limits_below_one_are_rejected() {
+        let mut spec = spec(json!([]));
+        spec.scale_up = serde_json::from_value(json!({"maxReplicasPerStep": 0})).unwrap();
+        spec.scale_down = serde_json::from_value(json!({"maxPercentPerStep": -5, "stabilizationDelay": "soon"})).unwrap();
+        let errors = validate(&spec, curr_ts());
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].starts_with("scaleUp.maxReplicasPerStep: 0 is out of range"));
+        assert!(errors[1].starts_with("scaleDown.maxPercentPerStep: -5 is out of range"));
+    }
+
+    #[test]
+    fn metric_targets_are_checked_against_their_type() {
+        let mut spec = spec(json!([]));
+        spec.hpa.metrics = serde_json::from_value(json!([
+            {"type": "External", "name": "rps", "target": {"type": "Value"}},
+            {"type": "Pods", "name": "queue_depth", "target": {"type": "AverageValue", "averageValue": "ten"}},
+            {"type": "Pods", "name": "latency", "target": {"type": "AverageValue", "averageValue": "-500m"}},
+            {"type": "Pods", "name": "inflight", "target": {"type": "AverageValue", "averageValue": "1.5k"}}
+        ])).unwrap();
+        let errors = validate(&spec, curr_ts());
+        assert_eq!(errors, vec![
+            "hpa.metrics[0].target.value: required for type Value".to_string(),
+            "hpa.metrics[1].target.averageValue: \"ten\" is not a quantity, Ex: 100 or 500m".to_string(),
+            "hpa.metrics[2].target.averageValue: \"-500m\" is not positive".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn metrics_name_what_their_type_requires() {
+        let mut spec = spec(json!([]));
+        spec.hpa.metrics = serde_json::from_value(json!([
+            {"type": "Object", "name": "requests", "target": {"type": "Value", "value": "100"}},
+            {"type": "ContainerResource", "name": "cpu", "target": {"type": "Utilization", "averageUtilization": 70}},
+            {"type": "ContainerResource", "name": "memory", "container": "", "target": {"type": "Utilization", "averageUtilization": 70}},
+            {"type": "Object", "name": "requests", "describedObject": {"apiVersion": "networking.k8s.io/v1", "kind": "Ingress", "name": "main"}, "target": {"type": "Value", "value": "100"}},
+            {"type": "ContainerResource", "name": "cpu", "container": "app", "target": {"type": "Utilization", "averageUtilization": 70}}
+        ])).unwrap();
+        let errors = validate(&spec, curr_ts());
+        assert_eq!(errors, vec![
+            "hpa.metrics[0].describedObject: required for type Object".to_string(),
+            "hpa.metrics[1].container: required for type ContainerResource".to_string(),
+            "hpa.metrics[2].container: required for type ContainerResource".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn overlapping_ranges_of_the_same_priority_are_rejected() {
+        let same_priority = spec(json!([zoned_time("09:00+05:30", "18:00+05:30", 0), zoned_time("17:00+05:30", "20:00+05:30", 0)]));
+        let errors = validate(&same_priority, curr_ts());
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].starts_with("timeRangeSpec[1]: overlaps timeRangeSpec[0]"), "{:?}", errors);
+
+        let distinct_priorities = spec(json!([zoned_time("09:00+05:30", "18:00+05:30", 0), zoned_time("17:00+05:30", "20:00+05:30", 1)]));
+        assert_eq!(validate(&distinct_priorities, curr_ts()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn calendar_entries_are_validated() {
+        let calendar: ScalingCalendarSpec = serde_json::from_value(json!({"entries": [
+            {"name": "sale", "from": "2024-03-08T00:00:00+05:30", "to": "2024-03-10T00:00:00+05:30", "replicaSpec": {"hpa": {"minReplicas": 8}}},
+            {"name": "holiday", "from": "2024-03-25", "to": "2024-03-26T00:00:00+05:30", "replicaSpec": {"hpa": {}}},
+            {"name": "inverted", "from": "2024-03-12T00:00:00+05:30", "to": "2024-03-11T00:00:00+05:30", "replicaSpec": {"hpa": {"minReplicas": 6, "maxReplicas": 3}}}
+        ]})).unwrap();
+        let errors = validate_calendar(&calendar, curr_ts());
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].starts_with("entries[1].from"), "{:?}", errors);
+        assert!(errors[1].starts_with("entries[2].replicaSpec.hpa: minReplicas 6 is greater than maxReplicas 3"), "{:?}", errors);
+    }
+}